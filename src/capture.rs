@@ -0,0 +1,196 @@
+//! USB traffic capture (pcapng) for protocol debugging
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+//
+// Captures use the USBPcap link type (as produced by Wireshark / USBPcap on windows),
+// so they can be compared directly against captures of the vendor driver.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Error;
+
+/// LINKTYPE_USBPCAP
+const LINKTYPE_USBPCAP: u16 = 249;
+
+/// URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER
+const URB_FUNCTION_BULK: u16 = 0x0009;
+
+/// USBPCAP_TRANSFER_BULK
+const TRANSFER_BULK: u8 = 3;
+
+/// USBD_STATUS_SUCCESS
+const STATUS_SUCCESS: u32 = 0x0000_0000;
+
+/// Generic USBD error status, used for failed transfers
+const STATUS_ERROR: u32 = 0xC000_0000;
+
+/// Length of the USBPcap packet header for bulk transfers
+const USBPCAP_HEADER_LEN: u16 = 27;
+
+/// pcapng capture writer for USB bulk transfers
+pub struct Capture {
+    w: BufWriter<File>,
+    irp: u64,
+    bus: u16,
+    device: u16,
+}
+
+impl Capture {
+    /// Create a capture file for the device at the provided bus and address
+    pub fn new<P: AsRef<Path>>(path: P, bus: u8, device: u8) -> Result<Self, Error> {
+        let f = File::create(path)?;
+
+        let mut c = Self {
+            w: BufWriter::new(f),
+            irp: 0,
+            bus: bus as u16,
+            device: device as u16,
+        };
+
+        // Section header block
+        let mut shb = vec![];
+        shb.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        c.block(0x0A0D_0D0A, &shb)?;
+
+        // Interface description block (default microsecond timestamps)
+        let mut idb = vec![];
+        idb.extend_from_slice(&LINKTYPE_USBPCAP.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        c.block(0x0000_0001, &idb)?;
+
+        c.w.flush()?;
+
+        Ok(c)
+    }
+
+    /// Update the device bus and address (eg. following reconnection)
+    pub fn set_device(&mut self, bus: u8, device: u8) {
+        self.bus = bus as u16;
+        self.device = device as u16;
+    }
+
+    /// Record an OUT transfer (submission with data, then completion)
+    pub fn write(&mut self, endpoint: u8, data: &[u8], ok: bool) -> Result<(), Error> {
+        self.irp += 1;
+
+        self.packet(endpoint, false, STATUS_SUCCESS, data)?;
+        self.packet(endpoint, true, status(ok), &[])?;
+
+        self.w.flush()?;
+
+        Ok(())
+    }
+
+    /// Record an IN transfer (submission, then completion with data)
+    pub fn read(&mut self, endpoint: u8, data: &[u8], ok: bool) -> Result<(), Error> {
+        self.irp += 1;
+
+        self.packet(endpoint, false, STATUS_SUCCESS, &[])?;
+        self.packet(endpoint, true, status(ok), data)?;
+
+        self.w.flush()?;
+
+        Ok(())
+    }
+
+    /// Write a USBPcap packet in an enhanced packet block
+    fn packet(
+        &mut self,
+        endpoint: u8,
+        complete: bool,
+        status: u32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let mut p = vec![];
+
+        // USBPcap packet header
+        p.extend_from_slice(&USBPCAP_HEADER_LEN.to_le_bytes());
+        p.extend_from_slice(&self.irp.to_le_bytes());
+        p.extend_from_slice(&status.to_le_bytes());
+        p.extend_from_slice(&URB_FUNCTION_BULK.to_le_bytes());
+        p.push(complete as u8);
+        p.extend_from_slice(&self.bus.to_le_bytes());
+        p.extend_from_slice(&self.device.to_le_bytes());
+        p.push(endpoint);
+        p.push(TRANSFER_BULK);
+        p.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        p.extend_from_slice(data);
+
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        // Enhanced packet block
+        let mut epb = vec![];
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts as u32).to_le_bytes());
+        epb.extend_from_slice(&(p.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(p.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&p);
+
+        self.block(0x0000_0006, &epb)
+    }
+
+    /// Write a pcapng block, padding the body to 32-bits
+    fn block(&mut self, kind: u32, body: &[u8]) -> Result<(), Error> {
+        let pad = (4 - body.len() % 4) % 4;
+        let len = (12 + body.len() + pad) as u32;
+
+        self.w.write_all(&kind.to_le_bytes())?;
+        self.w.write_all(&len.to_le_bytes())?;
+        self.w.write_all(body)?;
+        self.w.write_all(&[0u8; 3][..pad])?;
+        self.w.write_all(&len.to_le_bytes())?;
+
+        Ok(())
+    }
+}
+
+fn status(ok: bool) -> u32 {
+    match ok {
+        true => STATUS_SUCCESS,
+        false => STATUS_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let d = tempdir::TempDir::new("ptouch-capture").unwrap();
+        let path = d.path().join("test.pcapng");
+
+        let mut c = Capture::new(&path, 1, 4).unwrap();
+        c.write(0x02, &[0x1b, 0x40], true).unwrap();
+        drop(c);
+
+        let b = std::fs::read(&path).unwrap();
+
+        // Section header and interface description blocks
+        assert_eq!(&b[0..8], &[0x0a, 0x0d, 0x0d, 0x0a, 28, 0, 0, 0]);
+        assert_eq!(&b[28..36], &[0x01, 0, 0, 0, 20, 0, 0, 0]);
+        assert_eq!(&b[36..38], &LINKTYPE_USBPCAP.to_le_bytes());
+
+        // Submit packet with data (27 byte header + 2 bytes data, padded)
+        assert_eq!(&b[48..56], &[0x06, 0, 0, 0, 64, 0, 0, 0]);
+        assert_eq!(&b[76..78], &[27, 0]);
+        assert_eq!(&b[103..105], &[0x1b, 0x40]);
+
+        // Completion packet without data
+        assert_eq!(&b[112..120], &[0x06, 0, 0, 0, 60, 0, 0, 0]);
+        assert_eq!(b.len(), 172);
+    }
+}
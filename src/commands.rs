@@ -8,7 +8,7 @@ use std::time::Duration;
 
 use log::{debug, trace};
 
-use crate::device::{AdvancedMode, CompressionMode, Mode, PrintInfo, VariousMode};
+use crate::device::{AdvancedMode, CompressionMode, Mode, Page, PrintInfo, Setting, VariousMode};
 use crate::{device::Status, Error, PTouch};
 
 /// Raw command API for the PTouch device.
@@ -38,6 +38,9 @@ pub trait Commands {
     /// Set print information
     fn set_print_info(&mut self, info: &PrintInfo) -> Result<(), Error>;
 
+    /// Set print information for a page in a multi-page job
+    fn set_page_info(&mut self, info: &PrintInfo, page: Page) -> Result<(), Error>;
+
     /// Set various mode flags
     fn set_various_mode(&mut self, mode: VariousMode) -> Result<(), Error>;
 
@@ -50,6 +53,15 @@ pub trait Commands {
     /// Set print page number
     fn set_page_no(&mut self, no: u8) -> Result<(), Error>;
 
+    /// Request a printer setting, the response is read with `read_setting`
+    fn setting_req(&mut self, setting: Setting) -> Result<(), Error>;
+
+    /// Read a printer setting response with the provided timeout
+    fn read_setting(&mut self, setting: Setting, timeout: Duration) -> Result<u8, Error>;
+
+    /// Write a printer setting
+    fn set_setting(&mut self, setting: Setting, value: u8) -> Result<(), Error>;
+
     /// Set compression mode (None or Tiff).
     /// Note TIFF mode is currently... broken
     fn set_compression_mode(&mut self, mode: CompressionMode) -> Result<(), Error>;
@@ -110,9 +122,13 @@ impl Commands for PTouch {
     }
 
     fn set_print_info(&mut self, info: &PrintInfo) -> Result<(), Error> {
+        self.set_page_info(info, Page::First)
+    }
+
+    fn set_page_info(&mut self, info: &PrintInfo, page: Page) -> Result<(), Error> {
         let mut buff = [0u8; 13];
 
-        debug!("Set print info: {:?}", info);
+        debug!("Set print info: {:?} (page: {:?})", info, page);
 
         // Command header
         buff[0] = 0x1b;
@@ -137,6 +153,8 @@ impl Commands for PTouch {
         let raster_bytes = info.raster_no.to_le_bytes();
         buff[7..11].copy_from_slice(&raster_bytes);
 
+        buff[11] = page as u8;
+
         if info.recover {
             buff[3] |= 0x80;
         }
@@ -171,6 +189,38 @@ impl Commands for PTouch {
         self.write(&[0x1b, 0x69, 0x41, no], self.timeout)
     }
 
+    fn setting_req(&mut self, setting: Setting) -> Result<(), Error> {
+        debug!("Request setting: {:?}", setting);
+
+        self.write(
+            &[0x1b, 0x69, 0x58, 0x41, 0x01, 0x00, setting as u8],
+            self.timeout,
+        )
+    }
+
+    fn read_setting(&mut self, setting: Setting, timeout: Duration) -> Result<u8, Error> {
+        let resp = self.read(timeout)?;
+
+        trace!("Raw setting response: {:?}", &resp);
+
+        setting.decode(&resp).inspect_err(|_| {
+            debug!(
+                "Unexpected setting response for {:?}: {:02x?}",
+                setting,
+                &resp[..4]
+            );
+        })
+    }
+
+    fn set_setting(&mut self, setting: Setting, value: u8) -> Result<(), Error> {
+        debug!("Set setting: {:?} to {:?}", setting, value);
+
+        self.write(
+            &[0x1b, 0x69, 0x58, 0x4d, 0x02, 0x00, setting as u8, value],
+            self.timeout,
+        )
+    }
+
     fn set_compression_mode(&mut self, mode: CompressionMode) -> Result<(), Error> {
         debug!("Set compression mode: {:?}", mode);
 
@@ -4,6 +4,8 @@
 // https://github.com/ryankurte/rust-ptouch
 // Copyright 2021 Ryan Kurte
 
+use std::convert::TryFrom;
+
 use crate::Error;
 
 use bitflags::bitflags;
@@ -11,6 +13,9 @@ use bitflags::bitflags;
 #[cfg(feature = "strum")]
 use strum_macros::{Display, EnumString, EnumVariantNames};
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 bitflags::bitflags! {
     /// First error byte
     pub struct Error1: u8 {
@@ -48,6 +53,42 @@ pub enum PTouchDevice {
     PtD600 = 0x2074,
 }
 
+impl PTouchDevice {
+    /// All supported devices
+    pub const ALL: &'static [PTouchDevice] = &[
+        PTouchDevice::PtE550W,
+        PTouchDevice::PtE560BT,
+        PTouchDevice::PtP750W,
+        PTouchDevice::PtP710Bt,
+        PTouchDevice::PtD600,
+    ];
+
+    /// Check whether the device supports cutting every N labels (`ESC i A`)
+    pub fn supports_cut_every(&self) -> bool {
+        !matches!(self, PTouchDevice::PtP710Bt)
+    }
+
+    /// Check whether the device supports ESC/P mode (see [crate::escp])
+    pub fn supports_escp(&self) -> bool {
+        matches!(self, PTouchDevice::PtE550W | PTouchDevice::PtP750W)
+    }
+
+    /// Check whether the device supports P-touch Template mode (see [crate::template])
+    pub fn supports_template(&self) -> bool {
+        matches!(self, PTouchDevice::PtE550W | PTouchDevice::PtP750W)
+    }
+
+    /// Check whether the device has a half cutter (see [CutMode::HalfCut])
+    pub fn supports_half_cut(&self) -> bool {
+        !matches!(self, PTouchDevice::PtP710Bt | PTouchDevice::PtD600)
+    }
+
+    /// Fetch the device matching a USB product ID
+    pub fn from_pid(pid: u16) -> Option<Self> {
+        Self::ALL.iter().find(|d| **d as u16 == pid).copied()
+    }
+}
+
 /// Media width encoding for Status message
 #[derive(Copy, Clone, PartialEq, Debug)]
 #[cfg_attr(feature = "strum", derive(Display, EnumString, EnumVariantNames))]
@@ -133,7 +174,7 @@ impl Media {
         }
     }
 
-    /// Fetch the (approximate) media width in mm
+    /// Fetch the (approximate) media width in mm, 0 for unknown media
     pub fn width(&self) -> usize {
         use Media::*;
 
@@ -148,13 +189,15 @@ impl Media {
             Hs12mm => 12,
             Hs18mm => 18,
             Hs24mm => 24,
-            _ => panic!("Unknown media width"),
+            Unknown => 0,
         }
     }
 }
 
 /// Kind of media loaded in printer
 #[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum MediaKind {
     None = 0x00,
     LaminatedTape = 0x01,
@@ -432,6 +475,7 @@ impl From<[u8; 32]> for Status {
 
 /// Print information command
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PrintInfo {
     /// Media kind
     pub kind: Option<MediaKind>,
@@ -445,6 +489,12 @@ pub struct PrintInfo {
     pub recover: bool,
     /// No feed if chain is on, do not set for the last label
     pub chain: bool,
+    /// Number of copies of the raster data to print in the job
+    #[cfg_attr(feature = "serde", serde(default = "default_copies"))]
+    pub copies: u16,
+    /// Cut behaviour between copies
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub cut: CutMode,
 }
 
 impl Default for PrintInfo {
@@ -456,13 +506,160 @@ impl Default for PrintInfo {
             raster_no: 0,
             recover: true,
             chain: false,
+            copies: 1,
+            cut: CutMode::Each,
         }
     }
 }
 
+#[cfg(feature = "serde")]
+fn default_copies() -> u16 {
+    1
+}
+
+/// Cut behaviour for multi-label (copies) print jobs.
+/// A full cut is always made after the last label unless chain printing is enabled.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CutMode {
+    /// Cut after each label
+    #[default]
+    Each,
+    /// Cut after every N labels (1-99), only supported on some devices
+    /// (see [PTouchDevice::supports_cut_every])
+    Every(u8),
+    /// Half-cut between labels (leaving the backing intact), only supported on some devices
+    /// (see [PTouchDevice::supports_half_cut])
+    HalfCut,
+}
+
+/// Page position for print information in multi-page jobs
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Page {
+    First = 0x00,
+    Other = 0x01,
+    Last = 0x02,
+}
+
 /// Compression mode enumeration
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum CompressionMode {
     None = 0x00,
     Tiff = 0x02,
 }
+
+/// Persistent printer settings, see [crate::PTouch::settings].
+/// Note setting commands are not supported by all devices.
+///
+/// Auto power-off times are the only persistent settings documented in the raster
+/// command references for supported devices, other behaviour (auto cut, half cut,
+/// mirroring, margins, compression) is configured per job via [PrintInfo] and the
+/// print commands.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "strum", derive(Display, EnumString, EnumVariantNames))]
+#[cfg_attr(feature = "strum", strum(serialize_all = "kebab-case"))]
+pub enum Setting {
+    /// Auto power-off time when running on battery
+    AutoPowerOffBattery = 0x01,
+    /// Auto power-off time when running on AC adaptor
+    AutoPowerOffAc = 0x02,
+}
+
+impl Setting {
+    /// Decode a setting response, responses echo the setting ID followed by the value.
+    ///
+    /// Responses for other settings or status responses (eg. on error) are rejected.
+    pub fn decode(&self, resp: &[u8]) -> Result<u8, Error> {
+        match resp {
+            [id, v, ..] if *id == *self as u8 => Ok(*v),
+            _ => Err(Error::InvalidSetting),
+        }
+    }
+
+    /// Fetch settings supported by a given device
+    pub fn supported(device: PTouchDevice) -> &'static [Setting] {
+        use PTouchDevice::*;
+
+        match device {
+            PtE550W | PtE560BT | PtP750W | PtP710Bt => {
+                &[Setting::AutoPowerOffBattery, Setting::AutoPowerOffAc]
+            }
+            PtD600 => &[Setting::AutoPowerOffAc],
+        }
+    }
+}
+
+/// Auto power-off time
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "strum", derive(Display, EnumString, EnumVariantNames))]
+pub enum AutoPowerOff {
+    #[cfg_attr(feature = "strum", strum(serialize = "off"))]
+    Off = 0x00,
+    #[cfg_attr(feature = "strum", strum(serialize = "10"))]
+    Min10 = 0x01,
+    #[cfg_attr(feature = "strum", strum(serialize = "20"))]
+    Min20 = 0x02,
+    #[cfg_attr(feature = "strum", strum(serialize = "30"))]
+    Min30 = 0x03,
+    #[cfg_attr(feature = "strum", strum(serialize = "40"))]
+    Min40 = 0x04,
+    #[cfg_attr(feature = "strum", strum(serialize = "50"))]
+    Min50 = 0x05,
+    #[cfg_attr(feature = "strum", strum(serialize = "60"))]
+    Min60 = 0x06,
+}
+
+impl AutoPowerOff {
+    /// Fetch the power-off time in minutes (None if disabled)
+    pub fn minutes(&self) -> Option<u32> {
+        match self {
+            AutoPowerOff::Off => None,
+            v => Some(*v as u32 * 10),
+        }
+    }
+}
+
+impl TryFrom<u8> for AutoPowerOff {
+    type Error = Error;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        use AutoPowerOff::*;
+
+        match v {
+            0x00 => Ok(Off),
+            0x01 => Ok(Min10),
+            0x02 => Ok(Min20),
+            0x03 => Ok(Min30),
+            0x04 => Ok(Min40),
+            0x05 => Ok(Min50),
+            0x06 => Ok(Min60),
+            _ => Err(Error::InvalidSetting),
+        }
+    }
+}
+
+/// Printer settings, unset values are not supported (when read) or left unchanged (when written)
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Settings {
+    /// Auto power-off time when running on battery
+    pub auto_power_off_battery: Option<AutoPowerOff>,
+    /// Auto power-off time when running on AC adaptor
+    pub auto_power_off_ac: Option<AutoPowerOff>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_setting_decode() {
+        let s = Setting::AutoPowerOffAc;
+        assert_eq!(s.decode(&[0x02, 0x03, 0x00]).unwrap(), 0x03);
+
+        // Mismatched settings and status responses are rejected
+        assert!(s.decode(&[0x01, 0x03]).is_err());
+        assert!(s.decode(&[0x80, 0x20, 0x42]).is_err());
+        assert!(s.decode(&[0x02]).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+//! Device diagnostics, for debugging connection and permission issues
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use log::debug;
+use rusb::{Context, UsbContext};
+
+use crate::device::PTouchDevice;
+use crate::lock::DeviceLock;
+use crate::{Error, BROTHER_VID};
+
+/// Diagnostic check outcome
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Diagnostic check result
+#[derive(Clone, PartialEq, Debug)]
+pub struct Check {
+    /// Check name
+    pub name: String,
+    /// Check outcome
+    pub outcome: Outcome,
+    /// Explanation of the outcome, and how to resolve failures
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, outcome: Outcome, detail: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            outcome,
+            detail: detail.to_string(),
+        }
+    }
+}
+
+/// Check visibility, permissions, kernel driver binding and interface claim for
+/// all connected devices.
+///
+/// This does not reset or otherwise send commands to devices, and any kernel
+/// driver detached to check interface claiming is reattached. Devices locked by
+/// another process (see [crate::PTouch]) are not detached or claimed.
+pub fn diagnose() -> Vec<Check> {
+    match Context::new() {
+        Ok(c) => diagnose_with_context(&c),
+        Err(e) => vec![Check::new(
+            "usb",
+            Outcome::Fail,
+            &format!(
+                "Unable to initialise libusb ({}), check USB devices are available (eg. /dev/bus/usb is mounted)",
+                e
+            ),
+        )],
+    }
+}
+
+/// Run diagnostics using an existing rusb::Context, see [diagnose]
+pub fn diagnose_with_context(context: &Context) -> Vec<Check> {
+    let mut checks = vec![];
+
+    // Find supported devices
+    let devices = match context.devices() {
+        Ok(d) => d,
+        Err(e) => {
+            checks.push(Check::new(
+                "usb",
+                Outcome::Fail,
+                &format!(
+                    "Unable to list USB devices ({}), check libusb is available",
+                    e
+                ),
+            ));
+            return checks;
+        }
+    };
+
+    let mut found = 0;
+
+    for d in devices.iter() {
+        let desc = match d.device_descriptor() {
+            Ok(d) => d,
+            Err(e) => {
+                debug!("Could not fetch descriptor for device {:?}: {:?}", d, e);
+                continue;
+            }
+        };
+
+        if desc.vendor_id() != BROTHER_VID {
+            continue;
+        }
+
+        let name = format!("bus {} device {}", d.bus_number(), d.address());
+
+        // Visibility
+        let kind = match PTouchDevice::from_pid(desc.product_id()) {
+            Some(k) => k,
+            None => {
+                checks.push(Check::new(
+                    &name,
+                    Outcome::Warn,
+                    &format!(
+                        "Brother device with unsupported PID {:04x}, this may be another printer or a PTouch in a different (eg. mass storage) mode",
+                        desc.product_id()
+                    ),
+                ));
+                continue;
+            }
+        };
+
+        found += 1;
+
+        checks.push(Check::new(
+            &name,
+            Outcome::Pass,
+            &format!(
+                "Found {:?} ({:04x}:{:04x})",
+                kind,
+                BROTHER_VID,
+                desc.product_id()
+            ),
+        ));
+
+        // Permissions
+        let handle = match d.open() {
+            Ok(h) => {
+                checks.push(Check::new(&name, Outcome::Pass, "Device opened"));
+                h
+            }
+            Err(rusb::Error::Access) => {
+                checks.push(Check::new(&name, Outcome::Fail,
+                    "Permission denied opening device, install udev rules (`ptouch-util doctor --udev`) or run as root"));
+                continue;
+            }
+            Err(e) => {
+                checks.push(Check::new(
+                    &name,
+                    Outcome::Fail,
+                    &format!("Failed to open device: {}", e),
+                ));
+                continue;
+            }
+        };
+
+        // Device lock, held while detaching and claiming so a print in progress
+        // in another process is not interrupted
+        let _lock = match DeviceLock::acquire(&d, &handle, &desc, Duration::ZERO) {
+            Ok(l) => Some(l),
+            Err(Error::Busy) => {
+                checks.push(Check::new(
+                    &name,
+                    Outcome::Warn,
+                    "Device in use by another process, skipping kernel driver and interface checks",
+                ));
+                continue;
+            }
+            Err(e) => {
+                checks.push(Check::new(
+                    &name,
+                    Outcome::Warn,
+                    &format!("Unable to check device lock: {}", e),
+                ));
+                None
+            }
+        };
+
+        // Kernel driver binding (usblp)
+        let detached = match handle.kernel_driver_active(0) {
+            Ok(true) => match handle.detach_kernel_driver(0) {
+                Ok(_) => {
+                    checks.push(Check::new(&name, Outcome::Warn,
+                        "Kernel driver (usblp) bound, this is detached on connect and reattached on close (or skipped with `--usb-no-detach`)"));
+                    true
+                }
+                Err(e) => {
+                    checks.push(Check::new(
+                        &name,
+                        Outcome::Fail,
+                        &format!(
+                            "Kernel driver (usblp) bound and could not be detached: {}",
+                            e
+                        ),
+                    ));
+                    continue;
+                }
+            },
+            Ok(false) => {
+                checks.push(Check::new(&name, Outcome::Pass, "No kernel driver bound"));
+                false
+            }
+            Err(rusb::Error::NotSupported) => {
+                checks.push(Check::new(
+                    &name,
+                    Outcome::Pass,
+                    "Kernel driver detection not supported on this platform",
+                ));
+                false
+            }
+            Err(e) => {
+                checks.push(Check::new(
+                    &name,
+                    Outcome::Warn,
+                    &format!("Unable to check kernel driver: {}", e),
+                ));
+                false
+            }
+        };
+
+        // Interface claim
+        match handle.claim_interface(0) {
+            Ok(_) => {
+                checks.push(Check::new(&name, Outcome::Pass, "Interface claimed"));
+                let _ = handle.release_interface(0);
+            }
+            Err(rusb::Error::Busy) => {
+                checks.push(Check::new(&name, Outcome::Fail,
+                    "Interface busy, another process (eg. CUPS or another ptouch-util) is using the printer"));
+            }
+            Err(e) => {
+                checks.push(Check::new(
+                    &name,
+                    Outcome::Fail,
+                    &format!("Failed to claim interface: {}", e),
+                ));
+            }
+        }
+
+        // Hand the device back to the kernel
+        if detached {
+            if let Err(e) = handle.attach_kernel_driver(0) {
+                checks.push(Check::new(
+                    &name,
+                    Outcome::Warn,
+                    &format!("Failed to reattach kernel driver: {}", e),
+                ));
+            }
+        }
+    }
+
+    if found == 0 {
+        checks.push(Check::new("usb", Outcome::Fail,
+            "No supported devices found, check the printer is on and connected via USB (bluetooth is not supported)"));
+    }
+
+    checks
+}
+
+/// Generate udev rules granting access to all supported devices.
+///
+/// Install to `/etc/udev/rules.d/` then reload with
+/// `udevadm control --reload-rules && udevadm trigger`.
+pub fn udev_rules() -> String {
+    let mut s = String::new();
+
+    let _ = writeln!(s, "# Brother PTouch label printers (rust-ptouch)");
+
+    for d in PTouchDevice::ALL {
+        let _ = writeln!(s, "# {:?}", d);
+        let _ = writeln!(
+            s,
+            "SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", MODE=\"0660\", GROUP=\"plugdev\", TAG+=\"uaccess\"",
+            BROTHER_VID, *d as u16
+        );
+    }
+
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_udev_rules() {
+        let r = udev_rules();
+
+        assert_eq!(
+            r.lines().filter(|l| l.starts_with("SUBSYSTEM")).count(),
+            PTouchDevice::ALL.len()
+        );
+        assert!(r.contains("ATTRS{idVendor}==\"04f9\", ATTRS{idProduct}==\"20af\""));
+    }
+}
@@ -0,0 +1,270 @@
+//! ESC/P text mode support, printing with the device's resident fonts
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+//
+// See the Brother ESC/P Command Reference for the PT-E550W/P750W.
+// Note that ESC/P mode is only supported on the PT-E550W and PT-P750W.
+
+use std::path::Path;
+
+use log::debug;
+
+#[cfg(feature = "strum")]
+use strum_macros::{Display, EnumString, EnumVariantNames};
+
+use crate::commands::Commands;
+use crate::device::Mode;
+use crate::progress::CancelToken;
+use crate::{Error, PTouch};
+
+/// Resident (printer built-in) fonts
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "strum", derive(Display, EnumString, EnumVariantNames))]
+#[cfg_attr(feature = "strum", strum(serialize_all = "kebab-case"))]
+pub enum Font {
+    /// Brougham (bitmap)
+    Brougham = 0x00,
+    /// Letter Gothic Bold (bitmap)
+    LetterGothicBold = 0x01,
+    /// Brussels (bitmap)
+    Brussels = 0x02,
+    /// Helsinki (bitmap)
+    Helsinki = 0x03,
+    /// San Diego (bitmap)
+    SanDiego = 0x04,
+    /// Letter Gothic (outline)
+    LetterGothic = 0x09,
+    /// Brussels (outline)
+    BrusselsOutline = 0x0a,
+    /// Helsinki (outline)
+    HelsinkiOutline = 0x0b,
+}
+
+/// Line alignment
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Align {
+    Left = 0x00,
+    Centre = 0x01,
+    Right = 0x02,
+}
+
+/// Barcode symbologies generated on-printer
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "strum", derive(Display, EnumString, EnumVariantNames))]
+#[cfg_attr(feature = "strum", strum(serialize_all = "kebab-case"))]
+pub enum BarcodeKind {
+    Code39 = b'0' as isize,
+    Itf = b'1' as isize,
+    /// EAN-8, EAN-13 or UPC-A (by data length)
+    Ean = b'5' as isize,
+    UpcE = b'6' as isize,
+    Codabar = b'9' as isize,
+    Code128 = b'a' as isize,
+    Gs1_128 = b'b' as isize,
+}
+
+/// Narrowest bar width
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BarWidth {
+    ExtraSmall = b'0' as isize,
+    Small = b'1' as isize,
+    Medium = b'2' as isize,
+    Large = b'3' as isize,
+}
+
+/// On-printer barcode options
+#[derive(Clone, PartialEq, Debug)]
+pub struct BarcodeOptions {
+    /// Barcode symbology
+    pub kind: BarcodeKind,
+    /// Barcode height in dots
+    pub height: u16,
+    /// Narrowest bar width
+    pub width: BarWidth,
+    /// Print human readable characters below the barcode
+    pub text: bool,
+}
+
+impl Default for BarcodeOptions {
+    fn default() -> Self {
+        Self {
+            kind: BarcodeKind::Code39,
+            height: 48,
+            width: BarWidth::Small,
+            text: false,
+        }
+    }
+}
+
+/// ESC/P command sequence builder.
+///
+/// The resulting sequence is a complete job (mode switch, initialisation, content,
+/// then print), sent with [PTouch::print_escp] or written out with [EscP::save].
+#[derive(Clone, PartialEq, Debug)]
+pub struct EscP {
+    buff: Vec<u8>,
+}
+
+impl Default for EscP {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EscP {
+    /// Create a new command sequence
+    pub fn new() -> Self {
+        let mut buff = vec![];
+
+        // Switch to ESC/P mode
+        buff.extend_from_slice(&[0x1b, 0x69, 0x61, Mode::EscP as u8]);
+        // Initialise
+        buff.extend_from_slice(&[0x1b, 0x40]);
+
+        Self { buff }
+    }
+
+    /// Select a resident font
+    pub fn font(&mut self, font: Font) -> &mut Self {
+        self.buff.extend_from_slice(&[0x1b, 0x6b, font as u8]);
+        self
+    }
+
+    /// Set the character size in dots
+    pub fn size(&mut self, dots: u16) -> &mut Self {
+        self.buff
+            .extend_from_slice(&[0x1b, 0x58, 0x00, dots as u8, (dots >> 8) as u8]);
+        self
+    }
+
+    /// Enable or disable bold
+    pub fn bold(&mut self, enabled: bool) -> &mut Self {
+        let c = match enabled {
+            true => 0x45,
+            false => 0x46,
+        };
+        self.buff.extend_from_slice(&[0x1b, c]);
+        self
+    }
+
+    /// Enable or disable italic
+    pub fn italic(&mut self, enabled: bool) -> &mut Self {
+        let c = match enabled {
+            true => 0x34,
+            false => 0x35,
+        };
+        self.buff.extend_from_slice(&[0x1b, c]);
+        self
+    }
+
+    /// Enable or disable underline
+    pub fn underline(&mut self, enabled: bool) -> &mut Self {
+        self.buff.extend_from_slice(&[0x1b, 0x2d, enabled as u8]);
+        self
+    }
+
+    /// Set line alignment
+    pub fn align(&mut self, align: Align) -> &mut Self {
+        self.buff.extend_from_slice(&[0x1b, 0x61, align as u8]);
+        self
+    }
+
+    /// Add text, characters outside of printable ASCII are replaced with `?`
+    pub fn text(&mut self, text: &str) -> &mut Self {
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.newline();
+            }
+
+            self.buff.extend(line.chars().map(|c| match c {
+                ' '..='~' => c as u8,
+                _ => b'?',
+            }));
+        }
+        self
+    }
+
+    /// Start a new line
+    pub fn newline(&mut self) -> &mut Self {
+        self.buff.extend_from_slice(&[0x0d, 0x0a]);
+        self
+    }
+
+    /// Add a barcode, generated by the printer
+    pub fn barcode(&mut self, code: &str, opts: &BarcodeOptions) -> &mut Self {
+        // Barcode parameters
+        self.buff.extend_from_slice(&[0x1b, 0x69]);
+        self.buff.extend_from_slice(&[b't', opts.kind as u8]);
+        self.buff.extend_from_slice(&[b'r', b'0' + opts.text as u8]);
+        self.buff
+            .extend_from_slice(&[b'h', opts.height as u8, (opts.height >> 8) as u8]);
+        self.buff.extend_from_slice(&[b'w', opts.width as u8]);
+
+        // Barcode data, `\` terminated
+        self.buff.push(b'B');
+        self.buff.extend(
+            code.bytes()
+                .filter(|b| (b' '..=b'~').contains(b) && *b != b'\\'),
+        );
+        self.buff.push(b'\\');
+
+        self
+    }
+
+    /// Fetch the command sequence, including the trailing print command
+    pub fn data(&self) -> Vec<u8> {
+        let mut d = self.buff.clone();
+        // Print page
+        d.push(0x0c);
+        d
+    }
+
+    /// Write the command sequence to a file for inspection (or printing elsewhere)
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, self.data())?;
+        Ok(())
+    }
+}
+
+impl PTouch {
+    /// Print an ESC/P command sequence using the printer's resident fonts,
+    /// only supported on some devices (see [crate::device::PTouchDevice::supports_escp])
+    pub fn print_escp(&mut self, cmds: &EscP) -> Result<(), Error> {
+        if !self.options.device.supports_escp() {
+            return Err(Error::UnsupportedMode(format!(
+                "ESC/P is not supported on {:?}",
+                self.options.device
+            )));
+        }
+
+        debug!("Printing ESC/P sequence ({} bytes)", cmds.buff.len());
+
+        self.write(&cmds.data(), self.timeout)?;
+
+        self.print_wait(&mut |_| (), &CancelToken::default())?;
+
+        // Return to raster mode for subsequent raster prints
+        self.switch_mode(Mode::Raster)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_escp_text() {
+        let mut e = EscP::new();
+        e.font(Font::Helsinki).size(32).bold(true).text("Hi\nµ");
+
+        assert_eq!(
+            e.data(),
+            vec![
+                0x1b, 0x69, 0x61, 0x00, 0x1b, 0x40, 0x1b, 0x6b, 0x03, 0x1b, 0x58, 0x00, 0x20, 0x00,
+                0x1b, 0x45, b'H', b'i', 0x0d, 0x0a, b'?', 0x0c,
+            ]
+        );
+    }
+}
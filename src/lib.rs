@@ -3,17 +3,22 @@
 // https://github.com/ryankurte/rust-ptouch
 // Copyright 2021 Ryan Kurte
 
-use std::time::Duration;
+use std::convert::TryFrom;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 use commands::Commands;
 use device::Status;
 use image::ImageError;
-use log::{debug, trace};
+use log::{debug, trace, warn};
 
 #[cfg(feature = "clap")]
 use clap::Parser;
 
-use rusb::{Context, Device, DeviceDescriptor, DeviceHandle, Direction, TransferType, UsbContext};
+use rusb::{
+    Context, Device, DeviceDescriptor, DeviceHandle, Direction, Hotplug, HotplugBuilder,
+    TransferType, UsbContext,
+};
 
 pub mod device;
 use device::*;
@@ -22,13 +27,42 @@ pub mod commands;
 
 pub mod bitmap;
 
+pub mod media;
+
+pub mod preflight;
+
 pub mod tiff;
 
 pub mod render;
 
+pub mod escp;
+
+pub mod template;
+
+#[cfg(feature = "spool")]
+pub mod spool;
+
+pub mod queue;
+
+pub mod progress;
+use progress::{CancelToken, Progress};
+
+mod lock;
+use lock::DeviceLock;
+
+pub mod capture;
+use capture::Capture;
+
+pub mod doctor;
+
+#[cfg(feature = "async")]
+pub mod nonblocking;
+
 /// PTouch device instance
 pub struct PTouch {
     _device: Device<Context>,
+    context: Context,
+    options: Options,
     handle: DeviceHandle<Context>,
     descriptor: DeviceDescriptor,
     //endpoints: Endpoints,
@@ -36,6 +70,17 @@ pub struct PTouch {
 
     cmd_ep: u8,
     stat_ep: u8,
+
+    interface: u8,
+    claimed: bool,
+    detached: bool,
+    reset_on_close: bool,
+
+    lock: Option<DeviceLock>,
+
+    capture: Option<Capture>,
+
+    media: media::MediaRegistry,
 }
 
 /// Brother USB Vendor ID
@@ -61,6 +106,18 @@ pub struct Options {
     /// Do not reset the device on connect
     pub no_reset: bool,
 
+    #[cfg_attr(feature = "clap", arg(long, hide = true))]
+    /// Do not reset the device on close
+    pub no_reset_on_close: bool,
+
+    #[cfg_attr(feature = "clap", arg(long, default_value = "0"))]
+    /// Time to wait for another process to release the device (0 to fail immediately)
+    pub lock_timeout_seconds: u64,
+
+    #[cfg_attr(feature = "clap", arg(long, hide = true))]
+    /// (DEBUG) Do not lock the device against use by other processes
+    pub no_lock: bool,
+
     #[cfg_attr(feature = "clap", arg(long, hide = true))]
     /// (DEBUG) Do not claim USB interface on connect
     pub usb_no_claim: bool,
@@ -72,6 +129,14 @@ pub struct Options {
     #[cfg_attr(feature = "clap", arg(long))]
     /// If true, the program will not perform a status request
     pub no_status_fetch: bool,
+
+    #[cfg_attr(feature = "clap", arg(long))]
+    /// Record USB traffic to the specified pcapng file
+    pub capture: Option<String>,
+
+    #[cfg_attr(feature = "clap", arg(long, default_value = "0"))]
+    /// Time to wait for the device to reconnect if it goes away between jobs (0 to disable)
+    pub reconnect_timeout_seconds: u64,
 }
 
 // Lazy initialised libusb context
@@ -105,9 +170,48 @@ pub enum Error {
     #[error("Renderer error")]
     Render,
 
+    #[error("Font error: {0}")]
+    Font(String),
+
     #[error("Operation timeout")]
     Timeout,
 
+    #[error("Printer queue closed")]
+    QueueClosed,
+
+    #[error("Queued job panicked: {0}")]
+    JobPanicked(String),
+
+    #[error("Printer busy (in use by another process)")]
+    Busy,
+
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    #[error("Invalid or unsupported setting")]
+    InvalidSetting,
+
+    #[error("Unsupported cut mode: {0}")]
+    UnsupportedCut(String),
+
+    #[error("Unsupported mode: {0}")]
+    UnsupportedMode(String),
+
+    #[error("Unknown media ({0:?}, {1}mm), add a media profile to support this")]
+    UnknownMedia(MediaKind, u8),
+
+    #[error("Invalid media profile: {0}")]
+    InvalidMedia(String),
+
+    #[error("Preflight check failed: {0}")]
+    Preflight(preflight::Report),
+
+    #[error("Invalid template job: {0}")]
+    InvalidTemplate(String),
+
+    #[error("Spool error: {0}")]
+    Spool(String),
+
     #[error("PTouch Error ({:?} {:?})", 0, 1)]
     PTouch(Error1, Error2),
 }
@@ -130,6 +234,50 @@ impl From<ImageError> for Error {
     }
 }
 
+/// Interval at which to re-check for devices while waiting
+const WAIT_POLL: Duration = Duration::from_millis(500);
+
+/// Interval at which to poll for print completion
+pub(crate) const PRINT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of status polls to wait for print completion
+pub(crate) const PRINT_POLL_ATTEMPTS: u32 = 10;
+
+/// Check a status message received while printing, returning true once the print is complete
+pub(crate) fn print_complete(
+    s: &Status,
+    progress: &mut dyn FnMut(&Progress),
+) -> Result<bool, Error> {
+    if !s.error1.is_empty() || !s.error2.is_empty() {
+        debug!("Print error: {:?} {:?}", s.error1, s.error2);
+        return Err(Error::PTouch(s.error1, s.error2));
+    }
+
+    if s.status_type == DeviceStatus::PhaseChange {
+        debug!("Phase change: {:?}", s.phase);
+        progress(&Progress::Phase(s.phase));
+    }
+
+    if s.status_type == DeviceStatus::Completed {
+        debug!("Print completed");
+        progress(&Progress::Completed);
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+/// Hotplug handler, notifies a waiting [PTouch::wait_for] of device arrival
+struct Arrival(mpsc::Sender<()>);
+
+impl<T: UsbContext> Hotplug<T> for Arrival {
+    fn device_arrived(&mut self, _device: Device<T>) {
+        let _ = self.0.send(());
+    }
+
+    fn device_left(&mut self, _device: Device<T>) {}
+}
+
 /// PTouch device information
 #[derive(Clone, Debug, PartialEq)]
 pub struct Info {
@@ -144,6 +292,117 @@ impl PTouch {
         Self::new_with_context(o, &CONTEXT)
     }
 
+    /// Wait up to `timeout` for a device matching the provided USB options to be connected,
+    /// useful for printers that power themselves off.
+    pub fn wait_for(o: &Options, timeout: Duration) -> Result<Self, Error> {
+        Self::wait_for_with_context(o, &CONTEXT, timeout)
+    }
+
+    /// Wait up to `timeout` for a matching device using an existing rusb::Context
+    pub fn wait_for_with_context(
+        o: &Options,
+        context: &Context,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let deadline = Instant::now() + timeout;
+
+        // Register for arrivals _before_ looking for the device so none are missed
+        let (tx, rx) = mpsc::channel();
+        let registration = match rusb::has_hotplug() {
+            true => {
+                let r = HotplugBuilder::new()
+                    .vendor_id(BROTHER_VID)
+                    .product_id(o.device as u16)
+                    .register::<Context, _>(context.clone(), Box::new(Arrival(tx)))?;
+                Some(r)
+            }
+            false => {
+                debug!("Hotplug not supported, falling back to polling");
+                None
+            }
+        };
+
+        loop {
+            match Self::new_with_context(o, context) {
+                Ok(p) => return Ok(p),
+                // Device not (or not yet) available, keep waiting
+                Err(Error::InvalidIndex) | Err(Error::Usb(rusb::Error::NoDevice)) => (),
+                Err(e) => return Err(e),
+            }
+
+            debug!("Waiting for device");
+
+            // Wait for a matching device to arrive
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    debug!("Timeout waiting for device");
+                    return Err(Error::Timeout);
+                }
+                let wait = (deadline - now).min(WAIT_POLL);
+
+                match &registration {
+                    Some(_) => context.handle_events(Some(wait))?,
+                    None => std::thread::sleep(wait),
+                }
+
+                if registration.is_none() || rx.try_recv().is_ok() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Re-open the device using the options it was created with, waiting up to
+    /// `reconnect_timeout_seconds` for it to re-appear.
+    pub fn reconnect(&mut self) -> Result<(), Error> {
+        debug!("Reconnecting to device");
+
+        // Keep holding our lock while waiting, the new handle inherits it so
+        // other processes can't take the printer if reconnection fails.
+        // Traffic capture is also carried over rather than re-creating the file.
+        let mut options = self.options.clone();
+        options.no_lock = true;
+        options.capture = None;
+
+        let timeout = Duration::from_secs(self.options.reconnect_timeout_seconds);
+        let mut p = Self::wait_for_with_context(&options, &self.context, timeout)?;
+
+        p.options = self.options.clone();
+        p.media = self.media.clone();
+        p.lock = self.lock.take();
+        p.capture = self.capture.take().map(|mut c| {
+            c.set_device(p._device.bus_number(), p._device.address());
+            c
+        });
+
+        // The old handle is gone, don't attempt to clean it up
+        self.reset_on_close = false;
+        self.claimed = false;
+        self.detached = false;
+
+        *self = p;
+
+        Ok(())
+    }
+
+    /// Run an operation, reconnecting and retrying once if the device has gone away
+    /// (and reconnection is enabled)
+    fn with_reconnect<R>(
+        &mut self,
+        mut f: impl FnMut(&mut Self) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        match f(self) {
+            Err(Error::Usb(rusb::Error::NoDevice))
+                if self.options.reconnect_timeout_seconds > 0 =>
+            {
+                self.reconnect()?;
+                f(self)
+            }
+            r => r,
+        }
+    }
+
     /// Create a new PTouch driver with the provided USB options and an existing rusb::Context
     pub fn new_with_context(o: &Options, context: &Context) -> Result<Self, Error> {
         // List available devices
@@ -190,11 +449,23 @@ impl PTouch {
         let handle = match device.open() {
             Ok(v) => v,
             Err(e) => {
-                debug!("Error opening device");
+                debug!("Error opening device (see `ptouch-util doctor` for diagnostics)");
                 return Err(e.into());
             }
         };
 
+        // Lock device so other processes don't interleave commands
+        let lock = match o.no_lock {
+            false => {
+                let timeout = Duration::from_secs(o.lock_timeout_seconds);
+                Some(DeviceLock::acquire(&device, &handle, &descriptor, timeout)?)
+            }
+            true => {
+                debug!("Device lock disabled");
+                None
+            }
+        };
+
         // Reset device
         if let Err(e) = handle.reset() {
             debug!("Error resetting device handle");
@@ -247,11 +518,13 @@ impl PTouch {
         // for now this is enabled through hidden config options...
         // needs testing and a cfg guard as appropriate
         debug!("Checking for active kernel driver");
+        let mut detached = false;
         match handle.kernel_driver_active(interface.number())? {
             true => {
                 if !o.usb_no_detach {
                     debug!("Detaching kernel driver");
                     handle.detach_kernel_driver(interface.number())?;
+                    detached = true;
                 } else {
                     debug!("Kernel driver detach disabled");
                 }
@@ -267,19 +540,43 @@ impl PTouch {
         // needs testing and a cfg guard as appropriate
         if !o.usb_no_claim {
             debug!("Claiming interface");
-            handle.claim_interface(interface.number())?;
+            if let Err(e) = handle.claim_interface(interface.number()) {
+                // Hand the interface back to the kernel if we took it
+                if detached {
+                    let _ = handle.attach_kernel_driver(interface.number());
+                }
+                return Err(e.into());
+            }
         } else {
             debug!("Claim interface disabled");
         }
 
+        // Setup traffic capture
+        let capture = match &o.capture {
+            Some(path) => {
+                debug!("Capturing USB traffic to: {}", path);
+                Some(Capture::new(path, device.bus_number(), device.address())?)
+            }
+            None => None,
+        };
+
         // Create device object
         let mut s = Self {
             _device: device,
+            context: context.clone(),
+            options: o.clone(),
             handle,
             descriptor,
             cmd_ep,
             stat_ep,
             timeout: Duration::from_millis(o.timeout_milliseconds),
+            interface: interface.number(),
+            claimed: !o.usb_no_claim,
+            detached,
+            reset_on_close: !o.no_reset_on_close,
+            lock,
+            capture,
+            media: media::MediaRegistry::default(),
         };
 
         // Unless we're skipping reset
@@ -333,7 +630,7 @@ impl PTouch {
     /// Fetch the device status
     pub fn status(&mut self) -> Result<Status, Error> {
         // Issue status request
-        self.status_req()?;
+        self.with_reconnect(|s| s.status_req())?;
 
         // Read status response
         let d = self.read(self.timeout)?;
@@ -346,99 +643,336 @@ impl PTouch {
         Ok(s)
     }
 
-    /// Setup the printer and print using raw raster data.
-    /// Print output must be shifted and in the correct bit-order for this function.
-    ///
-    /// TODO: this is too low level of an interface, should be replaced with higher-level apis
-    pub fn print_raw(&mut self, data: Vec<[u8; 16]>, info: &PrintInfo) -> Result<(), Error> {
-        // TODO: should we check info (and size) match status here?
+    /// Fetch persistent printer settings, unsupported settings are left unset
+    pub fn settings(&mut self) -> Result<Settings, Error> {
+        let mut settings = Settings::default();
 
-        // Print sequence from raster guide Section 2.1
-        // 1. Set to raster mode
-        self.switch_mode(Mode::Raster)?;
+        for s in Setting::supported(self.options.device) {
+            self.setting_req(*s)?;
+            let v = self.read_setting(*s, self.timeout)?;
+            let v = AutoPowerOff::try_from(v)?;
 
-        // 2. Enable status notification
-        self.set_status_notify(true)?;
+            match s {
+                Setting::AutoPowerOffBattery => settings.auto_power_off_battery = Some(v),
+                Setting::AutoPowerOffAc => settings.auto_power_off_ac = Some(v),
+            }
+        }
 
-        // 3. Set print information (media type etc.)
-        self.set_print_info(info)?;
+        debug!("Settings: {:?}", settings);
 
-        // 4. Set various mode settings
-        self.set_various_mode(VariousMode::AUTO_CUT)?;
+        Ok(settings)
+    }
 
-        // 5. Specify page number in "cut each * labels"
-        // Note this is not supported on the PT-P710BT
-        // TODO: add this for other printers
+    /// Write persistent printer settings, unset values are left unchanged
+    pub fn set_settings(&mut self, settings: &Settings) -> Result<(), Error> {
+        let supported = Setting::supported(self.options.device);
+
+        let values = [
+            (
+                Setting::AutoPowerOffBattery,
+                settings.auto_power_off_battery,
+            ),
+            (Setting::AutoPowerOffAc, settings.auto_power_off_ac),
+        ];
+
+        for (s, v) in values.iter() {
+            let v = match v {
+                Some(v) => *v,
+                None => continue,
+            };
+
+            if !supported.contains(s) {
+                debug!("Setting {:?} not supported on {:?}", s, self.options.device);
+                return Err(Error::InvalidSetting);
+            }
 
-        // 6. Set advanced mode settings
-        if info.chain {
-            self.set_advanced_mode(AdvancedMode::NONE)?;
-        } else {
-            self.set_advanced_mode(AdvancedMode::NO_CHAIN)?;
+            self.set_setting(*s, v as u8)?;
         }
 
-        // 7. Specify margin amount
-        // TODO: based on what?
-        self.set_margin(0)?;
-
-        // 8. Set compression mode
-        // TODO: fix broken TIFF mode and add compression flag
-        self.set_compression_mode(CompressionMode::None)?;
+        Ok(())
+    }
 
-        // Send raster data
-        for line in data {
-            // TODO: re-add when TIFF mode issues resolved
-            //let l = tiff::compress(&line);
+    /// Setup the printer and print using raw raster data.
+    /// Print output must be shifted and in the correct bit-order for this function.
+    ///
+    /// Device status, loaded media (see [PTouch::set_media_registry]) and the raster
+    /// data are checked before anything is sent, returning [Error::Preflight] on errors.
+    ///
+    /// TODO: this is too low level of an interface, should be replaced with higher-level apis
+    pub fn print_raw(&mut self, data: Vec<[u8; 16]>, info: &PrintInfo) -> Result<(), Error> {
+        self.print_raw_with(data, info, |_| (), &CancelToken::default())
+    }
 
-            self.raster_transfer(&line)?;
+    /// Print using raw raster data (as with [PTouch::print_raw]), reporting progress
+    /// via the provided hook.
+    ///
+    /// If the cancel token is triggered raster transfer is stopped and the printer reset,
+    /// returning [Error::Cancelled].
+    pub fn print_raw_with(
+        &mut self,
+        data: Vec<[u8; 16]>,
+        info: &PrintInfo,
+        mut progress: impl FnMut(&Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        // Setup and send print data
+        self.print_start(data, info, &mut progress, cancel)?;
+
+        // Wait for each page to complete
+        for _ in 0..info.copies.max(1) {
+            self.print_wait(&mut progress, cancel)?;
         }
 
-        // Execute print operation
-        self.print_and_feed()?;
+        Ok(())
+    }
 
-        // Poll on print completion
+    /// Poll on print completion
+    pub(crate) fn print_wait(
+        &mut self,
+        progress: &mut dyn FnMut(&Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
         let mut i = 0;
         loop {
-            if let Ok(s) = self.read_status(self.timeout) {
-                if !s.error1.is_empty() || !s.error2.is_empty() {
-                    debug!("Print error: {:?} {:?}", s.error1, s.error2);
-                    return Err(Error::PTouch(s.error1, s.error2));
-                }
-
-                if s.status_type == DeviceStatus::PhaseChange {
-                    debug!("Started printing");
-                }
+            if cancel.is_cancelled() {
+                return self.print_cancel();
+            }
 
-                if s.status_type == DeviceStatus::Completed {
-                    debug!("Print completed");
+            if let Ok(s) = self.read_status(self.timeout) {
+                if print_complete(&s, progress)? {
                     break;
                 }
             }
 
-            if i > 10 {
+            if i > PRINT_POLL_ATTEMPTS {
                 debug!("Print timeout");
                 return Err(Error::Timeout);
             }
 
             i += 1;
 
-            std::thread::sleep(Duration::from_secs(1));
+            std::thread::sleep(PRINT_POLL_INTERVAL);
         }
 
         Ok(())
     }
 
+    /// Setup the printer, send raster data and start printing, without
+    /// waiting for the print to complete
+    pub(crate) fn print_start(
+        &mut self,
+        data: Vec<[u8; 16]>,
+        info: &PrintInfo,
+        progress: &mut dyn FnMut(&Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        // Check cut mode is supported
+        match info.cut {
+            CutMode::Every(_) if !self.options.device.supports_cut_every() => {
+                return Err(Error::UnsupportedCut(format!(
+                    "cut every N labels is not supported on {:?}",
+                    self.options.device
+                )));
+            }
+            CutMode::Every(n) if n == 0 || n > 99 => {
+                return Err(Error::UnsupportedCut(format!(
+                    "cut every {} labels outside 1..=99",
+                    n
+                )));
+            }
+            CutMode::HalfCut if !self.options.device.supports_half_cut() => {
+                return Err(Error::UnsupportedCut(format!(
+                    "half cut is not supported on {:?}",
+                    self.options.device
+                )));
+            }
+            _ => (),
+        }
+
+        // Check device status, loaded media and the raster data before sending anything
+        // (reconnecting if the device went away between jobs)
+        if !self.options.no_status_fetch {
+            let status = self.with_reconnect(|s| s.status())?;
+            let mut report = preflight::Report::from_status(&status, &self.media);
+            report.check_raster(&data);
+
+            for w in report.warnings() {
+                warn!("Preflight: {}", w);
+            }
+
+            report.into_result()?;
+        }
+
+        // Print sequence from raster guide Section 2.1
+        // 1. Set to raster mode (reconnecting if the device went away between jobs)
+        self.with_reconnect(|s| s.switch_mode(Mode::Raster))?;
+
+        // 2. Enable status notification
+        self.set_status_notify(true)?;
+
+        // Steps 3-8 and raster data are repeated for each page (copy)
+        let copies = info.copies.max(1);
+        let total = data.len() * copies as usize;
+        let mut sent = 0;
+        let mut bytes = 0;
+
+        for c in 0..copies {
+            let last = c + 1 == copies;
+            let page = match (c, last) {
+                (0, _) => Page::First,
+                (_, true) => Page::Last,
+                _ => Page::Other,
+            };
+
+            // 3. Set print information (media type etc.)
+            self.set_page_info(info, page)?;
+
+            // 4. Set various mode settings
+            self.set_various_mode(VariousMode::AUTO_CUT)?;
+
+            // 5. Specify page number in "cut each * labels"
+            // Note this is not supported on the PT-P710BT
+            if let CutMode::Every(n) = info.cut {
+                self.set_page_no(n)?;
+            }
+
+            // 6. Set advanced mode settings
+            let mut mode = match info.chain {
+                true => AdvancedMode::NONE,
+                false => AdvancedMode::NO_CHAIN,
+            };
+            if info.cut == CutMode::HalfCut {
+                mode |= AdvancedMode::HALF_CUT;
+            }
+            self.set_advanced_mode(mode)?;
+
+            // 7. Specify margin amount
+            // TODO: based on what?
+            self.set_margin(0)?;
+
+            // 8. Set compression mode
+            // TODO: fix broken TIFF mode and add compression flag
+            self.set_compression_mode(CompressionMode::None)?;
+
+            // Send raster data
+            for line in data.iter() {
+                if cancel.is_cancelled() {
+                    return self.print_cancel();
+                }
+
+                // TODO: re-add when TIFF mode issues resolved
+                //let l = tiff::compress(&line);
+
+                self.raster_transfer(line)?;
+
+                sent += 1;
+                bytes += line.len() + 3;
+                progress(&Progress::Raster { sent, total, bytes });
+            }
+
+            // Execute print operation, feeding (and cutting) after the last page
+            match last {
+                true => self.print_and_feed()?,
+                false => self.print()?,
+            }
+        }
+
+        progress(&Progress::Sent);
+
+        Ok(())
+    }
+
+    /// Abandon an in-progress print, resetting the printer
+    pub(crate) fn print_cancel(&mut self) -> Result<(), Error> {
+        debug!("Print cancelled, resetting device");
+
+        self.invalidate()?;
+        self.init()?;
+
+        Err(Error::Cancelled)
+    }
+
     pub fn cut(&mut self, info: &PrintInfo) -> Result<(), Error> {
         // Print a single blank line in chain mode, causing a minimal empty
         // label to be generated (which is cut off on the next print).
         let print_info = PrintInfo {
             chain: true,
+            copies: 1,
             ..*info
         };
 
         self.print_raw(vec![[0_u8; 16]], &print_info)
     }
 
+    /// Start recording USB traffic to a pcapng file, replacing any existing capture
+    pub fn start_capture<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<(), Error> {
+        let c = Capture::new(path, self._device.bus_number(), self._device.address())?;
+        self.capture = Some(c);
+        Ok(())
+    }
+
+    /// Stop recording USB traffic
+    pub fn stop_capture(&mut self) {
+        self.capture = None;
+    }
+
+    /// Close the device, resetting the printer (unless disabled), releasing
+    /// the claimed interface and reattaching any detached kernel driver.
+    ///
+    /// This is also called on drop, use `close` to observe any errors.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    /// Reset the printer and release USB resources, safe to call more than once.
+    ///
+    /// Each step is attempted independently (so a printer in an error state is still
+    /// handed back to the kernel), returning the first error. Failed release and
+    /// reattach steps are retried on the next call.
+    fn shutdown(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        // Leave the printer in a clean state for the next user, this is not retried
+        // as the interface may since have been released
+        if self.reset_on_close {
+            debug!("Resetting device on close");
+            self.reset_on_close = false;
+
+            if let Err(e) = self.invalidate().and_then(|_| self.init()) {
+                debug!("Error resetting device: {:?}", e);
+                result = result.and(Err(e));
+            }
+        }
+
+        // Release interface
+        if self.claimed {
+            debug!("Releasing interface");
+
+            match self.handle.release_interface(self.interface) {
+                Ok(_) => self.claimed = false,
+                Err(e) => {
+                    debug!("Error releasing interface: {:?}", e);
+                    result = result.and(Err(e.into()));
+                }
+            }
+        }
+
+        // Reattach kernel driver (so usblp / CUPS can find the printer again)
+        if self.detached {
+            debug!("Reattaching kernel driver");
+
+            match self.handle.attach_kernel_driver(self.interface) {
+                Ok(_) => self.detached = false,
+                Err(e) => {
+                    debug!("Error reattaching kernel driver: {:?}", e);
+                    result = result.and(Err(e.into()));
+                }
+            }
+        }
+
+        result
+    }
+
     /// Read from status EP (with specified timeout)
     fn read(&mut self, timeout: Duration) -> Result<[u8; 32], Error> {
         let mut buff = [0u8; 32];
@@ -450,7 +984,17 @@ impl PTouch {
                 return Err(Error::Timeout);
             }
             // Execute read
-            let n = self.handle.read_bulk(self.stat_ep, &mut buff, timeout)?;
+            let r = self.handle.read_bulk(self.stat_ep, &mut buff, timeout);
+
+            // Record transfer if capture is enabled
+            if let Some(c) = &mut self.capture {
+                let n = *r.as_ref().unwrap_or(&0);
+                if let Err(e) = c.read(self.stat_ep, &buff[..n], r.is_ok()) {
+                    warn!("Capture error: {:?}", e);
+                }
+            }
+
+            let n = r?;
             if n == 0 {
                 continue;
             } else {
@@ -468,7 +1012,16 @@ impl PTouch {
         debug!("WRITE: {:02x?}", data);
 
         // Execute write
-        let n = self.handle.write_bulk(self.cmd_ep, &data, timeout)?;
+        let r = self.handle.write_bulk(self.cmd_ep, data, timeout);
+
+        // Record transfer if capture is enabled
+        if let Some(c) = &mut self.capture {
+            if let Err(e) = c.write(self.cmd_ep, data, r.is_ok()) {
+                warn!("Capture error: {:?}", e);
+            }
+        }
+
+        let n = r?;
 
         // Check write length for timeouts
         if n != data.len() {
@@ -478,3 +1031,11 @@ impl PTouch {
         Ok(())
     }
 }
+
+impl Drop for PTouch {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            debug!("Error closing device: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Cross-process printer locking
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use fs4::fs_std::FileExt;
+use log::debug;
+use rusb::{Context, Device, DeviceDescriptor, DeviceHandle};
+
+use crate::Error;
+
+/// Interval at which to retry a held lock
+const LOCK_POLL: Duration = Duration::from_millis(100);
+
+/// Advisory lock on a printer device, held for the lifetime of a [crate::PTouch].
+///
+/// This is a lock file in the system temporary directory keyed on the device
+/// serial number (or bus location where this is unavailable), and is released on drop.
+pub(crate) struct DeviceLock {
+    _file: File,
+}
+
+impl DeviceLock {
+    /// Acquire the lock for a device, waiting up to `timeout` for any other holder to release it
+    pub fn acquire(
+        device: &Device<Context>,
+        handle: &DeviceHandle<Context>,
+        descriptor: &DeviceDescriptor,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let path = lock_path(device, handle, descriptor);
+
+        debug!("Acquiring device lock: {}", path.display());
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            if file.try_lock_exclusive()? {
+                return Ok(Self { _file: file });
+            }
+
+            if Instant::now() >= deadline {
+                debug!("Device lock held by another process");
+                return Err(Error::Busy);
+            }
+
+            std::thread::sleep(LOCK_POLL);
+        }
+    }
+}
+
+/// Build the lock file path for a device
+fn lock_path(
+    device: &Device<Context>,
+    handle: &DeviceHandle<Context>,
+    descriptor: &DeviceDescriptor,
+) -> PathBuf {
+    // Prefer the serial as this is stable across reconnection,
+    // falling back to the bus location if unavailable
+    let key = match handle.read_serial_number_string_ascii(descriptor) {
+        Ok(s) if !s.is_empty() => s
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect::<String>(),
+        _ => {
+            let ports = device.port_numbers().unwrap_or_default();
+            let ports: Vec<_> = ports.iter().map(|p| p.to_string()).collect();
+            format!("bus{}-{}", device.bus_number(), ports.join("."))
+        }
+    };
+
+    std::env::temp_dir().join(format!(
+        "ptouch-{:04x}-{}.lock",
+        descriptor.product_id(),
+        key
+    ))
+}
@@ -0,0 +1,219 @@
+//! Media profiles, describing the printable area of loaded media
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::device::{Media, MediaKind};
+use crate::Error;
+
+/// Number of pins on the print head (and bits in a raster line)
+pub const PRINT_HEAD_PINS: usize = 128;
+
+/// Media profile, describing a kind / width of media and it's printable area
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MediaProfile {
+    /// Profile name
+    pub name: String,
+    /// Media kinds matching this profile (as reported in status), empty to match any
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kinds: Vec<MediaKind>,
+    /// Media width in mm (as reported in status)
+    pub width: u8,
+    /// Unprinted pins before the print area
+    pub left: usize,
+    /// Printable pins
+    pub pins: usize,
+    /// Unprinted pins after the print area
+    pub right: usize,
+    /// Label length in mm for die-cut labels (unset for continuous tape)
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub length: Option<u8>,
+}
+
+impl MediaProfile {
+    /// Fetch media print area (left margin, print area, right margin)
+    pub fn area(&self) -> (usize, usize, usize) {
+        (self.left, self.pins, self.right)
+    }
+
+    /// Check whether the profile matches the provided media kind and width
+    pub fn matches(&self, kind: MediaKind, width: u8) -> bool {
+        self.width == width && (self.kinds.is_empty() || self.kinds.contains(&kind))
+    }
+
+    /// Check the profile fits the print head
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.pins == 0 || self.left + self.pins + self.right > PRINT_HEAD_PINS {
+            return Err(Error::InvalidMedia(format!(
+                "{}: print area ({}, {}, {}) must be within {} pins",
+                self.name, self.left, self.pins, self.right, PRINT_HEAD_PINS
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Create a profile from a built-in media type
+impl From<Media> for MediaProfile {
+    fn from(m: Media) -> Self {
+        let kinds = match m.is_tape() {
+            true => vec![
+                MediaKind::LaminatedTape,
+                MediaKind::NonLaminatedTape,
+                MediaKind::FlexibleTape,
+            ],
+            false => vec![MediaKind::HeatShrinkTube],
+        };
+        let (left, pins, right) = m.area();
+
+        Self {
+            name: format!("{:?}", m).to_lowercase(),
+            kinds,
+            width: m.width() as u8,
+            left,
+            pins,
+            right,
+            length: None,
+        }
+    }
+}
+
+/// Media registry file format
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MediaConfig {
+    pub media: Vec<MediaProfile>,
+}
+
+/// Registry of known media profiles, used to resolve media reported by the printer.
+///
+/// This contains the built-in [Media] types by default, and may be extended with
+/// user profiles (which take precedence) for third-party or unusual media.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MediaRegistry {
+    profiles: Vec<MediaProfile>,
+}
+
+impl Default for MediaRegistry {
+    fn default() -> Self {
+        use Media::*;
+
+        let builtin = [
+            Tze6mm, Tze9mm, Tze12mm, Tze18mm, Tze24mm, Hs6mm, Hs9mm, Hs12mm, Hs18mm, Hs24mm,
+        ];
+
+        Self {
+            profiles: builtin.iter().map(|m| MediaProfile::from(*m)).collect(),
+        }
+    }
+}
+
+impl MediaRegistry {
+    /// Add a profile, taking precedence over existing profiles
+    pub fn add(&mut self, profile: MediaProfile) -> Result<(), Error> {
+        profile.validate()?;
+
+        self.profiles.insert(0, profile);
+
+        Ok(())
+    }
+
+    /// Load profiles from a TOML file (see [MediaConfig]) into the registry
+    #[cfg(feature = "toml")]
+    pub fn load<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<(), Error> {
+        let s = std::fs::read_to_string(path)?;
+        let c: MediaConfig = toml::from_str(&s).map_err(|e| Error::InvalidMedia(e.to_string()))?;
+
+        for p in c.media {
+            self.add(p)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetch all profiles, in lookup order
+    pub fn profiles(&self) -> &[MediaProfile] {
+        &self.profiles
+    }
+
+    /// Find a profile by name
+    pub fn find(&self, name: &str) -> Option<&MediaProfile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Lookup the profile for media reported in device status
+    pub fn lookup(&self, kind: MediaKind, width: u8) -> Result<&MediaProfile, Error> {
+        self.profiles
+            .iter()
+            .find(|p| p.matches(kind, width))
+            .ok_or(Error::UnknownMedia(kind, width))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_media_lookup() {
+        let mut r = MediaRegistry::default();
+
+        // Built-in media
+        let p = r.lookup(MediaKind::LaminatedTape, 12).unwrap();
+        assert_eq!(p.area(), Media::Tze12mm.area());
+
+        // Unknown media
+        assert!(matches!(
+            r.lookup(MediaKind::IncompatibleTape, 12),
+            Err(Error::UnknownMedia(MediaKind::IncompatibleTape, 12))
+        ));
+
+        // User profiles take precedence
+        r.add(MediaProfile {
+            name: "fle-21x45".to_string(),
+            kinds: vec![MediaKind::IncompatibleTape],
+            width: 21,
+            left: 10,
+            pins: 108,
+            right: 10,
+            length: Some(45),
+        })
+        .unwrap();
+        assert_eq!(
+            r.lookup(MediaKind::IncompatibleTape, 21).unwrap().name,
+            "fle-21x45"
+        );
+
+        // Profiles must fit the print head
+        let mut p = MediaProfile::from(Media::Tze24mm);
+        p.left = 1;
+        assert!(r.add(p).is_err());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_media_config() {
+        let c: MediaConfig = toml::from_str(
+            r#"
+            [[media]]
+            name = "acme-12mm"
+            kinds = ["non_laminated_tape"]
+            width = 12
+            left = 30
+            pins = 68
+            right = 30
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(c.media[0].kinds, vec![MediaKind::NonLaminatedTape]);
+        assert_eq!(c.media[0].area(), (30, 68, 30));
+        assert_eq!(c.media[0].length, None);
+    }
+}
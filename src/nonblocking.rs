@@ -0,0 +1,177 @@
+//! Async (tokio) PTouch API
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+
+use crate::commands::Commands;
+use crate::device::{PrintInfo, Status};
+use crate::progress::{CancelToken, Progress};
+use crate::{print_complete, Error, Info, Options, PRINT_POLL_ATTEMPTS, PRINT_POLL_INTERVAL};
+
+/// Async PTouch device instance.
+///
+/// This wraps the blocking [crate::PTouch] driver, running USB transfers on
+/// the tokio blocking thread pool so commands are encoded identically.
+/// Futures may be dropped to cancel an operation, any transfer already
+/// in progress completes before the next operation is started.
+#[derive(Clone)]
+pub struct PTouch {
+    inner: Arc<Mutex<crate::PTouch>>,
+    print_timeout: Duration,
+}
+
+impl From<crate::PTouch> for PTouch {
+    fn from(p: crate::PTouch) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(p)),
+            print_timeout: PRINT_POLL_INTERVAL * (PRINT_POLL_ATTEMPTS + 1),
+        }
+    }
+}
+
+impl PTouch {
+    /// Create a new async PTouch driver with the provided USB options
+    pub async fn new(o: &Options) -> Result<Self, Error> {
+        let o = o.clone();
+        let p = tokio::task::spawn_blocking(move || crate::PTouch::new(&o))
+            .await
+            .map_err(std::io::Error::from)??;
+
+        Ok(p.into())
+    }
+
+    /// Wait up to `timeout` for a device matching the provided USB options to be connected
+    pub async fn wait_for(o: &Options, timeout: Duration) -> Result<Self, Error> {
+        let o = o.clone();
+        let p = tokio::task::spawn_blocking(move || crate::PTouch::wait_for(&o, timeout))
+            .await
+            .map_err(std::io::Error::from)??;
+
+        Ok(p.into())
+    }
+
+    /// Set the maximum time to wait for each copy of a print to complete
+    pub fn set_print_timeout(&mut self, timeout: Duration) {
+        self.print_timeout = timeout;
+    }
+
+    /// Run a blocking operation against the underlying device.
+    ///
+    /// This provides async access to the low-level [Commands] API.
+    pub async fn with<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut crate::PTouch) -> Result<R, Error> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+
+        tokio::task::spawn_blocking(move || {
+            // A poisoned lock means a previous operation panicked mid-transfer,
+            // the device state is no worse than any other failed transfer
+            let mut p = match inner.lock() {
+                Ok(p) => p,
+                Err(e) => e.into_inner(),
+            };
+            f(&mut p)
+        })
+        .await
+        .map_err(std::io::Error::from)?
+    }
+
+    /// Fetch device information
+    pub async fn info(&self) -> Result<Info, Error> {
+        self.with(|p| p.info()).await
+    }
+
+    /// Fetch the device status
+    pub async fn status(&self) -> Result<Status, Error> {
+        self.with(|p| p.status()).await
+    }
+
+    /// Setup the printer and print using raw raster data, see [crate::PTouch::print_raw].
+    ///
+    /// Completion is polled without blocking the executor, returning [Error::Timeout]
+    /// if the print does not complete within the print timeout (for each copy).
+    pub async fn print_raw(&self, data: Vec<[u8; 16]>, info: &PrintInfo) -> Result<(), Error> {
+        self.print_raw_with(data, info, |_| (), CancelToken::default())
+            .await
+    }
+
+    /// Print using raw raster data with a progress hook and cancel token,
+    /// see [crate::PTouch::print_raw_with]
+    pub async fn print_raw_with<F>(
+        &self,
+        data: Vec<[u8; 16]>,
+        info: &PrintInfo,
+        mut progress: F,
+        cancel: CancelToken,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&Progress) + Send + 'static,
+    {
+        let info = info.clone();
+        let c = cancel.clone();
+        let mut pages = info.copies.max(1);
+        let timeout = self.print_timeout * pages as u32;
+
+        // Setup and send print data, returning the hook for use while polling
+        let mut progress = self
+            .with(move |p| {
+                p.print_start(data, &info, &mut progress, &c)?;
+                Ok(progress)
+            })
+            .await?;
+
+        // Poll on print completion
+        let poll = async {
+            loop {
+                if cancel.is_cancelled() {
+                    return self.with(|p| p.print_cancel()).await;
+                }
+
+                let s = self
+                    .with(|p| {
+                        let timeout = p.timeout;
+                        p.read_status(timeout)
+                    })
+                    .await;
+
+                if let Ok(s) = s {
+                    if print_complete(&s, &mut progress)? {
+                        pages -= 1;
+                    }
+                    if pages == 0 {
+                        return Ok(());
+                    }
+                }
+
+                tokio::time::sleep(PRINT_POLL_INTERVAL).await;
+            }
+        };
+
+        match tokio::time::timeout(timeout, poll).await {
+            Ok(r) => r,
+            Err(_) => {
+                debug!("Print timeout");
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    /// Feed and cut the label, see [crate::PTouch::cut]
+    pub async fn cut(&self, info: &PrintInfo) -> Result<(), Error> {
+        let print_info = PrintInfo {
+            chain: true,
+            copies: 1,
+            ..info.clone()
+        };
+
+        self.print_raw(vec![[0_u8; 16]], &print_info).await
+    }
+}
@@ -0,0 +1,316 @@
+//! Preflight checks, ensuring a job matches the printer state before printing
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+use std::fmt;
+
+use log::debug;
+
+use crate::device::{Error1, Error2, MediaKind, Status, TapeColour};
+use crate::media::{MediaProfile, MediaRegistry, PRINT_HEAD_PINS};
+use crate::{Error, PTouch};
+
+/// Preflight issue
+#[derive(Clone, PartialEq, Debug)]
+pub enum Issue {
+    /// Device reported errors (other than weak battery)
+    DeviceError(Error1, Error2),
+    /// No media loaded
+    NoMedia,
+    /// Loaded media does not match any media profile
+    UnknownMedia(MediaKind, u8),
+    /// Loaded tape can not be printed on (cleaning, stencil or incompatible)
+    UnsupportedTape(TapeColour),
+    /// Label height (in pins) does not match the loaded media
+    SizeMismatch { height: usize, pins: usize },
+    /// Raster data (pins) falls outside the printable area of the loaded media
+    OutsidePrintArea {
+        first: usize,
+        last: usize,
+        area: (usize, usize, usize),
+    },
+    /// Battery is weak, printing may fail
+    WeakBattery,
+}
+
+impl Issue {
+    /// Check whether the issue prevents printing (rather than being a warning)
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Issue::WeakBattery)
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::DeviceError(e1, e2) => write!(f, "device error ({:?} {:?})", e1, e2),
+            Issue::NoMedia => write!(f, "no media loaded"),
+            Issue::UnknownMedia(k, w) => write!(f, "unknown media ({:?}, {}mm)", k, w),
+            Issue::UnsupportedTape(c) => write!(f, "unsupported tape loaded ({:?})", c),
+            Issue::SizeMismatch { height, pins } => write!(
+                f,
+                "label height ({} pins) does not match loaded media ({} pins)",
+                height, pins
+            ),
+            Issue::OutsidePrintArea { first, last, area } => write!(
+                f,
+                "raster data (pins {}..={}) outside print area ({}..{})",
+                first,
+                last,
+                area.0,
+                area.0 + area.1
+            ),
+            Issue::WeakBattery => write!(f, "weak battery"),
+        }
+    }
+}
+
+/// Preflight report, describing issues found when checking a job against
+/// the device status
+#[derive(Clone, PartialEq, Debug)]
+pub struct Report {
+    /// Device status at the time of checking
+    pub status: Status,
+    /// Media profile for the loaded media (if known)
+    pub media: Option<MediaProfile>,
+    /// Issues found
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    /// Check device status and loaded media against a label of the provided
+    /// height (in pins)
+    pub fn new(status: &Status, registry: &MediaRegistry, height: usize) -> Self {
+        let mut report = Self::from_status(status, registry);
+        report.check_height(height);
+        report
+    }
+
+    /// Check device status and loaded media, without a label height (eg. for raw raster data)
+    pub fn from_status(status: &Status, registry: &MediaRegistry) -> Self {
+        let mut issues = vec![];
+
+        // Check device errors, weak battery is reported separately
+        let e1 = status.error1 - Error1::WEAK_BATT;
+        if !e1.is_empty() || !status.error2.is_empty() {
+            issues.push(Issue::DeviceError(e1, status.error2));
+        }
+        if status.error1.contains(Error1::WEAK_BATT) {
+            issues.push(Issue::WeakBattery);
+        }
+
+        // Check tape can be printed on
+        match status.tape_colour {
+            TapeColour::Cleaning | TapeColour::Stencil | TapeColour::Incompatible => {
+                issues.push(Issue::UnsupportedTape(status.tape_colour))
+            }
+            _ => (),
+        }
+
+        // Check media is loaded and matches the label
+        let media = match status.media_kind {
+            MediaKind::None => {
+                issues.push(Issue::NoMedia);
+                None
+            }
+            _ => match registry.lookup(status.media_kind, status.media_width) {
+                Ok(m) => Some(m.clone()),
+                Err(_) => {
+                    issues.push(Issue::UnknownMedia(status.media_kind, status.media_width));
+                    None
+                }
+            },
+        };
+
+        Self {
+            status: status.clone(),
+            media,
+            issues,
+        }
+    }
+
+    /// Check a label of the provided height (in pins) matches the loaded media
+    pub fn check_height(&mut self, height: usize) -> &mut Self {
+        if let Some(m) = &self.media {
+            if m.pins != height {
+                self.issues.push(Issue::SizeMismatch {
+                    height,
+                    pins: m.pins,
+                });
+            }
+        }
+
+        self
+    }
+
+    /// Check raster data falls within the printable area of the loaded media
+    pub fn check_raster(&mut self, data: &[[u8; 16]]) -> &mut Self {
+        let (first, last) = match raster_extent(data) {
+            Some(e) => e,
+            None => return self,
+        };
+
+        if let Some(m) = &self.media {
+            let (left, pins, _) = m.area();
+            if first < left || last >= left + pins {
+                self.issues.push(Issue::OutsidePrintArea {
+                    first,
+                    last,
+                    area: m.area(),
+                });
+            }
+        }
+
+        self
+    }
+
+    /// Check whether the job can be printed (there are no errors)
+    pub fn is_ok(&self) -> bool {
+        !self.issues.iter().any(|i| i.is_error())
+    }
+
+    /// Fetch issues that prevent printing
+    pub fn errors(&self) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(|i| i.is_error())
+    }
+
+    /// Fetch issues that do not prevent printing
+    pub fn warnings(&self) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(|i| !i.is_error())
+    }
+
+    /// Convert the report into a result, returning [Error::Preflight] on errors
+    pub fn into_result(self) -> Result<Self, Error> {
+        match self.is_ok() {
+            true => Ok(self),
+            false => Err(Error::Preflight(self)),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.issues.is_empty() {
+            return write!(f, "ok");
+        }
+
+        for (i, issue) in self.issues.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", issue)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Find the first and last pins set in raster data
+pub fn raster_extent(data: &[[u8; 16]]) -> Option<(usize, usize)> {
+    let mut extent: Option<(usize, usize)> = None;
+
+    for line in data {
+        for pin in 0..PRINT_HEAD_PINS {
+            // Raster lines are MSB first (see Display::raster)
+            if line[pin / 8] & (1 << (7 - pin % 8)) == 0 {
+                continue;
+            }
+
+            extent = Some(match extent {
+                Some((first, last)) => (first.min(pin), last.max(pin)),
+                None => (pin, pin),
+            });
+        }
+    }
+
+    extent
+}
+
+impl PTouch {
+    /// Set the media registry used to resolve loaded media when printing.
+    ///
+    /// Device status, loaded media and raster data are checked before any raster
+    /// data is sent (unless status fetching is disabled), see [Report::from_status].
+    pub fn set_media_registry(&mut self, registry: MediaRegistry) {
+        self.media = registry;
+    }
+
+    /// Fetch device status and check a label of the provided height (in pins)
+    /// can be printed on the loaded media.
+    ///
+    /// This sends no print data, see [Report::into_result] to fail on errors.
+    pub fn preflight(&mut self, registry: &MediaRegistry, height: usize) -> Result<Report, Error> {
+        let status = self.status()?;
+
+        let report = Report::new(&status, registry, height);
+
+        debug!("Preflight: {}", report);
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::Media;
+
+    #[test]
+    fn test_preflight() {
+        let r = MediaRegistry::default();
+        let mut s = Status::new(&Media::Tze12mm).unwrap();
+
+        // Matching media
+        let p = Report::new(&s, &r, 70);
+        assert!(p.issues.is_empty());
+
+        // 24mm layout on 12mm tape
+        let p = Report::new(&s, &r, 128);
+        assert_eq!(
+            p.issues,
+            vec![Issue::SizeMismatch {
+                height: 128,
+                pins: 70
+            }]
+        );
+        assert!(!p.is_ok());
+
+        // Status checks alone do not check the label height
+        assert!(Report::from_status(&s, &r).issues.is_empty());
+
+        // Raster outside the print area
+        let mut p = Report::new(&s, &r, 70);
+        let mut line = [0u8; 16];
+        line[0] = 0x80;
+        p.check_raster(&[line]);
+        assert_eq!(
+            p.issues,
+            vec![Issue::OutsidePrintArea {
+                first: 0,
+                last: 0,
+                area: (29, 70, 29)
+            }]
+        );
+
+        // Weak battery is a warning only
+        s.error1 = Error1::WEAK_BATT;
+        let p = Report::new(&s, &r, 70);
+        assert_eq!(p.issues, vec![Issue::WeakBattery]);
+        assert!(p.is_ok());
+
+        // Cleaning tape and device errors
+        s.error1 = Error1::WEAK_BATT | Error1::CUTTER_JAM;
+        s.tape_colour = TapeColour::Cleaning;
+        let p = Report::new(&s, &r, 70);
+        assert_eq!(
+            p.issues,
+            vec![
+                Issue::DeviceError(Error1::CUTTER_JAM, Error2::empty()),
+                Issue::WeakBattery,
+                Issue::UnsupportedTape(TapeColour::Cleaning),
+            ]
+        );
+    }
+}
@@ -0,0 +1,54 @@
+//! Print progress reporting and cancellation
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::device::Phase;
+
+/// Print progress events, passed to the progress hook of [crate::PTouch::print_raw_with]
+#[derive(Clone, PartialEq, Debug)]
+pub enum Progress {
+    /// Raster line transferred
+    Raster {
+        /// Number of raster lines sent
+        sent: usize,
+        /// Total number of raster lines in the job
+        total: usize,
+        /// Raster bytes transferred (including command headers)
+        bytes: usize,
+    },
+    /// Raster transfer complete, waiting for the printer
+    Sent,
+    /// Printer phase changed
+    Phase(Phase),
+    /// Print completed
+    Completed,
+}
+
+/// Cancellation token for print jobs.
+///
+/// Clones share the same state, so one may be handed to the printing
+/// thread while another is used to cancel (for example from a UI).
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Create a new (not cancelled) token
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation of any job using this token
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Check whether cancellation has been requested
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,262 @@
+//! Thread-safe printer job queue
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::debug;
+
+use crate::device::{PrintInfo, Status};
+use crate::{Error, PTouch};
+
+/// Print job for submission to a [PrinterQueue]
+#[derive(Clone, PartialEq, Debug)]
+pub enum Job {
+    /// Print raw raster data, see [PTouch::print_raw]
+    Print {
+        data: Vec<[u8; 16]>,
+        info: PrintInfo,
+    },
+    /// Feed and cut the label, see [PTouch::cut]
+    Cut { info: PrintInfo },
+}
+
+/// Queue worker state
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WorkerState {
+    /// Waiting for jobs
+    Idle,
+    /// Executing the job with the provided ID
+    Busy(u64),
+    /// Worker has exited
+    Stopped,
+}
+
+/// Queue status information
+#[derive(Clone, PartialEq, Debug)]
+pub struct QueueStatus {
+    /// Number of jobs waiting to be executed
+    pub depth: usize,
+    /// Current worker state
+    pub state: WorkerState,
+    /// Most recent device status, if available
+    pub printer: Option<Status>,
+}
+
+/// Handle to a submitted job, used to wait for completion
+pub struct JobHandle<T> {
+    id: u64,
+    rx: Receiver<Result<T, Error>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Fetch the job ID
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wait for the job to complete
+    pub fn wait(self) -> Result<T, Error> {
+        self.rx.recv().map_err(|_| Error::QueueClosed)?
+    }
+
+    /// Wait for the job to complete with the provided timeout.
+    /// On timeout the job is still queued, and may be waited on again.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<T, Error> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(r) => r,
+            Err(RecvTimeoutError::Timeout) => Err(Error::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::QueueClosed),
+        }
+    }
+}
+
+/// Queue request, sent to the worker thread
+enum Request {
+    Job(u64, Job, Sender<Result<(), Error>>),
+    Status(u64, Sender<Result<Status, Error>>),
+}
+
+/// Shared queue state
+struct State {
+    next_id: u64,
+    status: QueueStatus,
+}
+
+/// Thread-safe printer queue.
+///
+/// This owns a [PTouch] instance on a worker thread, executing jobs
+/// submitted from any number of (cloned) handles in order.
+/// The worker exits once all handles are dropped and queued jobs are complete.
+#[derive(Clone)]
+pub struct PrinterQueue {
+    tx: Sender<Request>,
+    state: Arc<Mutex<State>>,
+}
+
+impl PrinterQueue {
+    /// Create a new queue, moving the provided device to a worker thread
+    pub fn new(ptouch: PTouch) -> Self {
+        let (tx, rx) = mpsc::channel();
+
+        let state = Arc::new(Mutex::new(State {
+            next_id: 0,
+            status: QueueStatus {
+                depth: 0,
+                state: WorkerState::Idle,
+                printer: None,
+            },
+        }));
+
+        let s = state.clone();
+        thread::spawn(move || worker(ptouch, rx, s));
+
+        Self { tx, state }
+    }
+
+    /// Submit a job to the queue
+    pub fn submit(&self, job: Job) -> Result<JobHandle<()>, Error> {
+        let (tx, rx) = mpsc::channel();
+        let id = self.enqueue(|id| Request::Job(id, job, tx))?;
+
+        Ok(JobHandle { id, rx })
+    }
+
+    /// Submit a print job to the queue, see [PTouch::print_raw]
+    pub fn print_raw(&self, data: Vec<[u8; 16]>, info: &PrintInfo) -> Result<JobHandle<()>, Error> {
+        self.submit(Job::Print {
+            data,
+            info: info.clone(),
+        })
+    }
+
+    /// Submit a cut job to the queue, see [PTouch::cut]
+    pub fn cut(&self, info: &PrintInfo) -> Result<JobHandle<()>, Error> {
+        self.submit(Job::Cut { info: info.clone() })
+    }
+
+    /// Request a device status update, executed in order with other jobs
+    pub fn fetch_status(&self) -> Result<JobHandle<Status>, Error> {
+        let (tx, rx) = mpsc::channel();
+        let id = self.enqueue(|id| Request::Status(id, tx))?;
+
+        Ok(JobHandle { id, rx })
+    }
+
+    /// Fetch the current queue status
+    pub fn status(&self) -> QueueStatus {
+        lock(&self.state).status.clone()
+    }
+
+    /// Fetch the number of jobs waiting to be executed
+    pub fn depth(&self) -> usize {
+        lock(&self.state).status.depth
+    }
+
+    /// Allocate a job ID and send a request to the worker
+    fn enqueue(&self, f: impl FnOnce(u64) -> Request) -> Result<u64, Error> {
+        // Hold the state lock while sending so IDs are queued in order
+        let mut s = lock(&self.state);
+
+        let id = s.next_id;
+        s.next_id += 1;
+
+        self.tx.send(f(id)).map_err(|_| Error::QueueClosed)?;
+        s.status.depth += 1;
+
+        Ok(id)
+    }
+}
+
+/// Lock shared state, ignoring poisoning (the state is always left consistent)
+fn lock(state: &Mutex<State>) -> std::sync::MutexGuard<'_, State> {
+    match state.lock() {
+        Ok(s) => s,
+        Err(e) => e.into_inner(),
+    }
+}
+
+/// Run a request, converting panics to errors so the worker keeps running
+/// (the device state is no worse than after any other failed transfer)
+fn catch<T>(f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(r) => r,
+        Err(e) => {
+            let m = match (e.downcast_ref::<&str>(), e.downcast_ref::<String>()) {
+                (Some(m), _) => m.to_string(),
+                (_, Some(m)) => m.clone(),
+                _ => "unknown panic".to_string(),
+            };
+            Err(Error::JobPanicked(m))
+        }
+    }
+}
+
+/// Queue worker, executes requests in order until all senders are dropped
+fn worker(mut ptouch: PTouch, rx: Receiver<Request>, state: Arc<Mutex<State>>) {
+    // Mark the worker as stopped however it exits, queued requests are dropped
+    // with the receiver, failing their handles with [Error::QueueClosed]
+    let _stopped = Stopped(state.clone());
+
+    for req in rx.iter() {
+        let id = match &req {
+            Request::Job(id, ..) | Request::Status(id, ..) => *id,
+        };
+
+        {
+            let mut s = lock(&state);
+            s.status.depth -= 1;
+            s.status.state = WorkerState::Busy(id);
+        }
+
+        debug!("Executing queued job {}", id);
+
+        let status = match req {
+            Request::Job(_, job, tx) => {
+                let r = catch(|| match job {
+                    Job::Print { data, info } => ptouch.print_raw(data, &info),
+                    Job::Cut { info } => ptouch.cut(&info),
+                });
+                if let Err(e) = &r {
+                    debug!("Queued job {} failed: {:?}", id, e);
+                }
+
+                // Submitters may not wait for results
+                let _ = tx.send(r);
+
+                None
+            }
+            Request::Status(_, tx) => {
+                let r = catch(|| ptouch.status());
+                let status = r.as_ref().ok().cloned();
+
+                let _ = tx.send(r);
+
+                status
+            }
+        };
+
+        let mut s = lock(&state);
+        s.status.state = WorkerState::Idle;
+        if status.is_some() {
+            s.status.printer = status;
+        }
+    }
+
+    debug!("Printer queue closed");
+}
+
+/// Guard setting the worker state to [WorkerState::Stopped] on drop
+struct Stopped(Arc<Mutex<State>>);
+
+impl Drop for Stopped {
+    fn drop(&mut self) {
+        lock(&self.0).status.state = WorkerState::Stopped;
+    }
+}
@@ -0,0 +1,582 @@
+//! Bitmap font loading, supporting X11 BDF and PCF fonts
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+use crate::Error;
+
+/// PCF file header
+const PCF_MAGIC: &[u8] = b"\x01fcp";
+
+/// PCF table types
+const PCF_ACCELERATORS: u32 = 1 << 1;
+const PCF_METRICS: u32 = 1 << 2;
+const PCF_BITMAPS: u32 = 1 << 3;
+const PCF_BDF_ENCODINGS: u32 = 1 << 5;
+const PCF_BDF_ACCELERATORS: u32 = 1 << 8;
+
+/// PCF table format flags
+const PCF_GLYPH_PAD_MASK: u32 = 3;
+const PCF_BYTE_MASK: u32 = 1 << 2;
+const PCF_BIT_MASK: u32 = 1 << 3;
+const PCF_SCAN_UNIT_MASK: u32 = 3 << 4;
+const PCF_COMPRESSED_METRICS: u32 = 0x100;
+
+/// PCF encoding table entry for unmapped characters
+const PCF_NO_GLYPH: u16 = 0xffff;
+
+/// Bitmap font glyph
+#[derive(Clone, PartialEq, Debug)]
+pub struct BitmapGlyph {
+    /// Horizontal advance in pixels
+    pub advance: i32,
+    /// Bounding box width in pixels
+    pub width: u32,
+    /// Bounding box height in pixels
+    pub height: u32,
+    /// Bounding box offset from the origin (right of the pen position)
+    pub x_offset: i32,
+    /// Bounding box offset from the origin (up from the baseline)
+    pub y_offset: i32,
+    /// Glyph rows, MSB first and padded to whole bytes
+    pub rows: Vec<u8>,
+}
+
+impl BitmapGlyph {
+    /// Fetch the number of bytes per glyph row
+    fn stride(&self) -> usize {
+        (self.width as usize).div_ceil(8)
+    }
+
+    /// Check whether a pixel (from the top-left of the bounding box) is set
+    pub fn get(&self, x: u32, y: u32) -> bool {
+        let b = self.rows[y as usize * self.stride() + x as usize / 8];
+        b & (0x80 >> (x % 8)) != 0
+    }
+
+    /// Draw the glyph with the origin (pen position on the baseline) at the provided point
+    pub fn draw<D>(&self, d: &mut D, origin: Point) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let left = origin.x + self.x_offset;
+        let top = origin.y - self.y_offset - self.height as i32;
+
+        let mut pixels = vec![];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.get(x, y) {
+                    let p = Point::new(left + x as i32, top + y as i32);
+                    pixels.push(Pixel(p, BinaryColor::On));
+                }
+            }
+        }
+
+        d.draw_iter(pixels)
+    }
+}
+
+/// Bitmap font loaded from an X11 BDF or PCF file.
+///
+/// Glyph encodings are assumed to be unicode (ISO10646 or ISO8859-1).
+#[derive(Clone, PartialEq, Debug)]
+pub struct BitmapFont {
+    /// Distance from the top of a line to the baseline in pixels
+    pub ascent: i32,
+    /// Distance from the baseline to the bottom of a line in pixels
+    pub descent: i32,
+    glyphs: HashMap<char, BitmapGlyph>,
+}
+
+impl BitmapFont {
+    /// Load a font from a BDF (`.bdf`) or PCF (`.pcf`) file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+
+        let font = match ext.as_deref() {
+            Some("bdf") => Self::parse_bdf(&std::fs::read_to_string(path)?),
+            Some("pcf") => Self::parse_pcf(&std::fs::read(path)?),
+            _ => Err(Error::Font(format!(
+                "Unsupported bitmap font '{}', expected a .bdf or .pcf file",
+                path.display()
+            ))),
+        }?;
+
+        if font.glyphs.is_empty() {
+            return Err(Error::Font(format!(
+                "No glyphs found in '{}'",
+                path.display()
+            )));
+        }
+
+        Ok(font)
+    }
+
+    /// Fetch the glyph for a character
+    pub fn glyph(&self, c: char) -> Option<&BitmapGlyph> {
+        self.glyphs.get(&c)
+    }
+
+    /// Fetch the height of a line of text in pixels
+    pub fn height(&self) -> u32 {
+        (self.ascent + self.descent).max(0) as u32
+    }
+
+    /// Parse a font from BDF (Glyph Bitmap Distribution Format) text
+    pub fn parse_bdf(s: &str) -> Result<Self, Error> {
+        let err = |m: &str| Error::Font(format!("Invalid BDF font: {}", m));
+
+        let mut lines = s.lines().map(str::trim);
+
+        let mut bbox = None;
+        let mut ascent = None;
+        let mut descent = None;
+        let mut glyphs = HashMap::new();
+
+        while let Some(l) = lines.next() {
+            let mut f = l.split_whitespace();
+
+            match f.next() {
+                Some("FONTBOUNDINGBOX") => {
+                    bbox = Some(bdf_bbox(f).ok_or_else(|| err(l))?);
+                    continue;
+                }
+                Some("FONT_ASCENT") => {
+                    ascent = f.next().and_then(|v| v.parse().ok());
+                    continue;
+                }
+                Some("FONT_DESCENT") => {
+                    descent = f.next().and_then(|v| v.parse().ok());
+                    continue;
+                }
+                Some("STARTCHAR") => (),
+                _ => continue,
+            }
+
+            // Parse glyph properties and bitmap
+            let mut encoding = None;
+            let mut advance = None;
+            let mut bbx = bbox;
+            let mut rows = vec![];
+
+            loop {
+                let l = lines.next().ok_or_else(|| err("unterminated glyph"))?;
+                let mut f = l.split_whitespace();
+
+                match f.next() {
+                    Some("ENCODING") => encoding = f.next().and_then(|v| v.parse::<i32>().ok()),
+                    Some("DWIDTH") => advance = f.next().and_then(|v| v.parse::<i32>().ok()),
+                    Some("BBX") => bbx = Some(bdf_bbox(f).ok_or_else(|| err(l))?),
+                    Some("BITMAP") => {
+                        let (w, h, _, _) = bbx.ok_or_else(|| err("missing glyph bounds"))?;
+                        let stride = (w.max(0) as usize).div_ceil(8);
+
+                        for _ in 0..h {
+                            let r = lines.next().ok_or_else(|| err("short bitmap"))?;
+
+                            // Rows may be padded beyond the glyph width
+                            for i in 0..stride {
+                                let b = r
+                                    .get(i * 2..i * 2 + 2)
+                                    .map(|v| u8::from_str_radix(v, 16))
+                                    .unwrap_or(Ok(0))
+                                    .map_err(|_| err(r))?;
+                                rows.push(b);
+                            }
+                        }
+                    }
+                    Some("ENDCHAR") => break,
+                    _ => (),
+                }
+            }
+
+            // Skip glyphs without a standard encoding
+            let c = match encoding
+                .filter(|e| *e >= 0)
+                .and_then(|e| char::from_u32(e as u32))
+            {
+                Some(c) => c,
+                None => continue,
+            };
+            let (w, h, x_offset, y_offset) = bbx.ok_or_else(|| err("missing glyph bounds"))?;
+
+            // Glyphs without a bitmap are blank
+            let (width, height) = (w.max(0) as u32, h.max(0) as u32);
+            rows.resize((width as usize).div_ceil(8) * height as usize, 0);
+
+            glyphs.insert(
+                c,
+                BitmapGlyph {
+                    advance: advance.unwrap_or(w),
+                    width,
+                    height,
+                    x_offset,
+                    y_offset,
+                    rows,
+                },
+            );
+        }
+
+        // Default to the font bounding box where ascent / descent are unset
+        let ascent = ascent
+            .or(bbox.map(|b| b.1 + b.3))
+            .ok_or_else(|| err("missing FONT_ASCENT"))?;
+        let descent = descent
+            .or(bbox.map(|b| -b.3))
+            .ok_or_else(|| err("missing FONT_DESCENT"))?;
+
+        Ok(Self {
+            ascent,
+            descent,
+            glyphs,
+        })
+    }
+
+    /// Parse a font from PCF (X11 Portable Compiled Format) data.
+    ///
+    /// Compressed (`.pcf.gz`) fonts must be decompressed before loading.
+    pub fn parse_pcf(d: &[u8]) -> Result<Self, Error> {
+        let err = |m: &str| Error::Font(format!("Invalid PCF font: {}", m));
+
+        if !d.starts_with(PCF_MAGIC) {
+            return Err(err("bad header (compressed fonts are not supported)"));
+        }
+
+        // Read table of contents (always little-endian)
+        let mut r = PcfReader::new(d, PCF_MAGIC.len(), 0);
+        let count = r.u32()?;
+
+        let mut tables = HashMap::new();
+        for _ in 0..count {
+            let (kind, _format, _size, offset) = (r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+            tables.insert(kind, offset as usize);
+        }
+
+        let table = |kind: u32| -> Result<PcfReader, Error> {
+            let offset = *tables.get(&kind).ok_or_else(|| err("missing table"))?;
+
+            // Each table starts with its format (little-endian), which sets byte order for the remainder
+            let format = PcfReader::new(d, offset, 0).u32()?;
+            Ok(PcfReader::new(d, offset + 4, format))
+        };
+
+        // Glyph metrics
+        let mut r = table(PCF_METRICS)?;
+        let metrics = match r.format & PCF_COMPRESSED_METRICS != 0 {
+            true => {
+                let n = r.u16()? as usize;
+                let mut m = Vec::with_capacity(n);
+                for _ in 0..n {
+                    let mut v = [0i32; 5];
+                    for x in v.iter_mut() {
+                        *x = r.u8()? as i32 - 0x80;
+                    }
+                    m.push(v);
+                }
+                m
+            }
+            false => {
+                let n = r.u32()? as usize;
+                let mut m = Vec::with_capacity(n);
+                for _ in 0..n {
+                    let mut v = [0i32; 5];
+                    for x in v.iter_mut() {
+                        *x = r.u16()? as i16 as i32;
+                    }
+                    let _attributes = r.u16()?;
+                    m.push(v);
+                }
+                m
+            }
+        };
+
+        // Glyph bitmaps
+        let mut r = table(PCF_BITMAPS)?;
+        let n = r.u32()? as usize;
+        if n != metrics.len() {
+            return Err(err("bitmap and metric counts differ"));
+        }
+
+        let mut offsets = Vec::with_capacity(n);
+        for _ in 0..n {
+            offsets.push(r.u32()? as usize);
+        }
+
+        let mut sizes = [0usize; 4];
+        for s in sizes.iter_mut() {
+            *s = r.u32()? as usize;
+        }
+
+        let pad = 1 << (r.format & PCF_GLYPH_PAD_MASK);
+        let unit = 1 << ((r.format & PCF_SCAN_UNIT_MASK) >> 4);
+        let swap = (r.format & PCF_BYTE_MASK != 0) != (r.format & PCF_BIT_MASK != 0) && unit > 1;
+        let data = d
+            .get(r.pos..r.pos + sizes[(r.format & PCF_GLYPH_PAD_MASK) as usize])
+            .ok_or_else(|| err("short bitmap data"))?;
+
+        let mut bitmaps = Vec::with_capacity(n);
+        for (m, offset) in metrics.iter().zip(offsets.iter()) {
+            let [left, right, advance, ascent, descent] = *m;
+            let (width, height) = (
+                (right - left).max(0) as usize,
+                (ascent + descent).max(0) as usize,
+            );
+
+            // Source rows are padded to the glyph pad, output rows to whole bytes
+            let src_stride = width.div_ceil(pad * 8) * pad;
+            let stride = width.div_ceil(8);
+
+            let mut rows = Vec::with_capacity(stride * height);
+            for y in 0..height {
+                for x in 0..stride {
+                    // Swap bytes within scan units where byte and bit order differ
+                    let i = match swap {
+                        true => x / unit * unit + (unit - 1 - x % unit),
+                        false => x,
+                    };
+
+                    let b = *data
+                        .get(offset + y * src_stride + i)
+                        .ok_or_else(|| err("glyph outside bitmap data"))?;
+
+                    rows.push(match r.format & PCF_BIT_MASK != 0 {
+                        true => b,
+                        false => b.reverse_bits(),
+                    });
+                }
+            }
+
+            bitmaps.push(BitmapGlyph {
+                advance,
+                width: width as u32,
+                height: height as u32,
+                x_offset: left,
+                y_offset: -descent,
+                rows,
+            });
+        }
+
+        // Character encodings, mapping (two byte) codes to glyph indices
+        let mut r = table(PCF_BDF_ENCODINGS)?;
+        let (min2, max2) = (r.u16()? as u32, r.u16()? as u32);
+        let (min1, max1) = (r.u16()? as u32, r.u16()? as u32);
+        let _default = r.u16()?;
+
+        let mut glyphs = HashMap::new();
+        for b1 in min1..=max1 {
+            for b2 in min2..=max2 {
+                let i = r.u16()?;
+                if i == PCF_NO_GLYPH {
+                    continue;
+                }
+
+                if let (Some(c), Some(g)) = (char::from_u32(b1 << 8 | b2), bitmaps.get(i as usize))
+                {
+                    glyphs.insert(c, g.clone());
+                }
+            }
+        }
+
+        // Font ascent / descent from accelerators, or glyph metrics where missing
+        let (ascent, descent) =
+            match table(PCF_BDF_ACCELERATORS).or_else(|_| table(PCF_ACCELERATORS)) {
+                Ok(mut r) => {
+                    r.pos += 8;
+                    (r.u32()? as i32, r.u32()? as i32)
+                }
+                Err(_) => (
+                    metrics.iter().map(|m| m[3]).max().unwrap_or(0),
+                    metrics.iter().map(|m| m[4]).max().unwrap_or(0),
+                ),
+            };
+
+        Ok(Self {
+            ascent,
+            descent,
+            glyphs,
+        })
+    }
+}
+
+/// Parse a BDF bounding box (width, height, x offset, y offset)
+fn bdf_bbox<'a>(mut f: impl Iterator<Item = &'a str>) -> Option<(i32, i32, i32, i32)> {
+    let mut v = [0i32; 4];
+    for x in v.iter_mut() {
+        *x = f.next()?.parse().ok()?;
+    }
+    Some((v[0], v[1], v[2], v[3]))
+}
+
+/// Reader for PCF table data, with byte order from the table format
+struct PcfReader<'a> {
+    d: &'a [u8],
+    pos: usize,
+    format: u32,
+}
+
+impl<'a> PcfReader<'a> {
+    fn new(d: &'a [u8], pos: usize, format: u32) -> Self {
+        Self { d, pos, format }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut b = [0u8; N];
+        let s = self
+            .d
+            .get(self.pos..self.pos + N)
+            .ok_or_else(|| Error::Font("Invalid PCF font: unexpected end of data".to_string()))?;
+
+        b.copy_from_slice(s);
+        self.pos += N;
+
+        // Multi-byte values are MSB first where set in the format
+        if self.format & PCF_BYTE_MASK == 0 {
+            b.reverse();
+        }
+
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.bytes()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.bytes()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BDF: &str = "STARTFONT 2.1
+FONT -test-fixed
+SIZE 8 75 75
+FONTBOUNDINGBOX 4 6 0 -1
+STARTPROPERTIES 2
+FONT_ASCENT 5
+FONT_DESCENT 1
+ENDPROPERTIES
+CHARS 2
+STARTCHAR A
+ENCODING 65
+SWIDTH 500 0
+DWIDTH 5 0
+BBX 4 5 0 0
+BITMAP
+60
+90
+F0
+90
+90
+ENDCHAR
+STARTCHAR unmapped
+ENCODING -1
+DWIDTH 5 0
+BBX 4 5 0 0
+BITMAP
+F0
+F0
+F0
+F0
+F0
+ENDCHAR
+ENDFONT
+";
+
+    #[test]
+    fn test_bdf() {
+        let f = BitmapFont::parse_bdf(BDF).unwrap();
+        assert_eq!((f.ascent, f.descent, f.height()), (5, 1, 6));
+
+        let a = f.glyph('A').unwrap();
+        assert_eq!(a.advance, 5);
+        assert_eq!(a.rows, vec![0x60, 0x90, 0xf0, 0x90, 0x90]);
+        assert!(a.get(1, 0) && !a.get(0, 0));
+
+        assert!(f.glyph('B').is_none());
+    }
+
+    #[test]
+    fn test_pcf() {
+        // Single glyph 'A' (4x5, compressed metrics) with 4 byte padding, across
+        // byte and bit orders and scan units
+        let glyph = [0x60u8, 0x90, 0xf0, 0x90, 0x90];
+
+        for format in [0x02, 0x0a, 0x12, 0x16, 0x1a, 0x1e, 0x22, 0x26, 0x2a, 0x2e] {
+            let unit = 1usize << ((format & PCF_SCAN_UNIT_MASK) >> 4);
+            let (msb_byte, msb_bit) = (format & PCF_BYTE_MASK != 0, format & PCF_BIT_MASK != 0);
+            let u32b = |v: u32| match msb_byte {
+                true => v.to_be_bytes(),
+                false => v.to_le_bytes(),
+            };
+
+            let mut d = PCF_MAGIC.to_vec();
+            d.extend(3u32.to_le_bytes());
+
+            let tables = [
+                (PCF_METRICS, 4 + 2 + 5),
+                (PCF_BITMAPS, 4 + 4 + 4 + 16 + 20),
+                (PCF_BDF_ENCODINGS, 4 + 10 + 2),
+            ];
+            let mut offset = d.len() + tables.len() * 16;
+            for (kind, size) in tables {
+                for v in [kind, 0, size as u32, offset as u32] {
+                    d.extend(v.to_le_bytes());
+                }
+                offset += size;
+            }
+
+            d.extend((PCF_COMPRESSED_METRICS).to_le_bytes());
+            d.extend(1u16.to_le_bytes());
+            d.extend([0x80, 0x84, 0x85, 0x85, 0x80]);
+
+            d.extend(format.to_le_bytes());
+            d.extend(u32b(1));
+            d.extend(u32b(0));
+            for s in [20u32; 4] {
+                d.extend(u32b(s));
+            }
+            for r in glyph {
+                let r = match msb_bit {
+                    true => r,
+                    false => r.reverse_bits(),
+                };
+
+                // Bytes are swapped within scan units where byte and bit order differ
+                let mut row = [r, 0, 0, 0];
+                if msb_byte != msb_bit {
+                    row.chunks_mut(unit).for_each(|c| c.reverse());
+                }
+                d.extend(row);
+            }
+
+            d.extend(0u32.to_le_bytes());
+            for v in [65u16, 65, 0, 0, 0, 0] {
+                d.extend(v.to_le_bytes());
+            }
+
+            let f = BitmapFont::parse_pcf(&d).unwrap();
+            assert_eq!((f.ascent, f.descent), (5, 0));
+
+            let a = f.glyph('A').unwrap();
+            assert_eq!((a.width, a.height, a.advance), (4, 5, 5));
+            assert_eq!(a.rows, glyph.to_vec(), "format {:#x}", format);
+        }
+    }
+}
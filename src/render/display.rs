@@ -6,13 +6,18 @@
 
 use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
 
+use super::Orientation;
 use crate::Error;
 
-/// In memory display for drawing / rendering data
+/// In memory display for drawing / rendering data.
+///
+/// Pixels are stored along the tape (X) and across the print head (Y), with the
+/// orientation used to present the label as it is read (see [Display::get_view]).
 pub struct Display {
     y: usize,
     y_max: usize,
     data: Vec<Vec<u8>>,
+    orientation: Orientation,
 }
 
 impl Display {
@@ -27,9 +32,15 @@ impl Display {
             y,
             y_max,
             data: vec![vec![0u8; y_max / 8]; min_x],
+            orientation: Orientation::Landscape,
         }
     }
 
+    /// Set the orientation used when viewing the display
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.orientation = orientation;
+    }
+
     /// Fetch a flipped + compressed vector image for output to printer
     pub fn image(&self) -> Result<Vec<u8>, Error> {
         // Generate new buffer
@@ -134,6 +145,15 @@ impl Display {
         Ok(c & (1 << (y % 8) as u8) != 0)
     }
 
+    /// Fetch a pixel value by X/Y location as the label is read, rotating
+    /// portrait labels so the start of the tape is at the top
+    pub fn get_view(&self, x: usize, y: usize) -> Result<bool, Error> {
+        match self.orientation {
+            Orientation::Landscape => self.get(x, y),
+            Orientation::Portrait => self.get(y, self.y - 1 - x),
+        }
+    }
+
     /// Fetch a pixel value by X/Y location
     pub fn get_pixel(&self, x: usize, y: usize) -> Result<Pixel<BinaryColor>, Error> {
         let v = match self.get(x, y)? {
@@ -156,6 +176,15 @@ impl Display {
     pub fn populated_size(&self) -> Size {
         Size::new(self.data.len() as u32, self.y as u32)
     }
+
+    /// Fetch the size of the populated area as the label is read (see [Display::get_view])
+    pub fn view_size(&self) -> Size {
+        let s = self.populated_size();
+        match self.orientation {
+            Orientation::Landscape => s,
+            Orientation::Portrait => Size::new(s.height, s.width),
+        }
+    }
 }
 
 impl OriginDimensions for Display {
@@ -241,6 +270,18 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_display_view() {
+        let mut d = Display::new(8, 2);
+        d.set(0, 0, true).unwrap();
+        d.set_orientation(Orientation::Portrait);
+
+        // Start of the tape at the top, first pin on the right
+        assert_eq!(d.view_size(), Size::new(8, 2));
+        assert!(d.get_view(7, 0).unwrap());
+        assert!(!d.get_view(0, 0).unwrap());
+    }
+
     #[cfg(disabled)]
     #[test]
     fn test_raster() {
@@ -0,0 +1,384 @@
+//! Layout containers, composing render operations
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+use super::{GroupOptions, HAlign, Length, Op, Render, RenderConfig, Rotation, Row};
+use crate::Error;
+
+/// Item within a row, either rendered or sharing the remaining width by weight
+enum Item<'a> {
+    Rendered(Render, usize),
+    Flex { weight: u32, min: usize, op: &'a Op },
+}
+
+impl Render {
+    /// Create an empty nested render of the provided height
+    fn nested(&self, y: usize) -> Render {
+        Render::new(RenderConfig {
+            min_x: 0,
+            max_x: self.cfg.max_x,
+            y,
+            orientation: self.cfg.orientation,
+        })
+    }
+
+    /// Copy a nested render onto this render at the provided position, clipped to
+    /// `width` and extending this render to the full width (as with [Op::Pad])
+    pub(super) fn place(&mut self, r: &Render, origin: Point, width: usize) -> Result<(), Error> {
+        let size = r.display.populated_size();
+        let size = Size::new(size.width.min(width as u32), size.height);
+
+        self.blit(&r.display, size, origin, Rotation::None)?;
+
+        if width > 0 {
+            let end = Point::new(origin.x + width as i32 - 1, 0);
+            self.display.draw_pixel(Pixel(end, BinaryColor::Off))?;
+        }
+
+        Ok(())
+    }
+
+    /// Render a container op (see [Op::Group] and [Op::Stack]) into a nested render,
+    /// with the width allocated by the parent (or sized to the content), returning
+    /// the nested render and container width
+    pub(super) fn render_container(
+        &self,
+        op: &Op,
+        width: Option<usize>,
+    ) -> Result<(Render, usize), Error> {
+        match op {
+            Op::Group { ops, opts } => self.render_group(ops, opts, width),
+            Op::Stack { rows, opts } => self.render_stack(rows, opts, width),
+            _ => {
+                let mut r = self.nested(self.cfg.y);
+                let w = r.render_ops(std::slice::from_ref(op))?;
+                Ok((r, w))
+            }
+        }
+    }
+
+    /// Resolve the width of a container, from the allocated or fixed width if set
+    fn container_width(opts: &GroupOptions, width: Option<usize>) -> Option<usize> {
+        width
+            .or_else(|| opts.width.and_then(|w| w.pixels()))
+            .map(|w| opts.clamp(w))
+    }
+
+    fn render_group(
+        &self,
+        ops: &[Op],
+        opts: &GroupOptions,
+        width: Option<usize>,
+    ) -> Result<(Render, usize), Error> {
+        if let Some(w) = Self::container_width(opts, width) {
+            return self.layout_row(ops, Some(w), &opts.h_align);
+        }
+
+        // Sized to the content, re-laying out content outside the min / max width
+        let (r, w) = self.layout_row(ops, None, &opts.h_align)?;
+        match opts.clamp(w) {
+            c if c == w => Ok((r, w)),
+            c => self.layout_row(ops, Some(c), &opts.h_align),
+        }
+    }
+
+    fn render_stack(
+        &self,
+        rows: &[Row],
+        opts: &GroupOptions,
+        width: Option<usize>,
+    ) -> Result<(Render, usize), Error> {
+        let sizes: Vec<_> = rows.iter().map(|r| r.size).collect();
+        let heights = Length::distribute(&sizes, self.cfg.y);
+
+        // Sized to the widest row unless a width is set
+        let width = match Self::container_width(opts, width) {
+            Some(w) => w,
+            None => {
+                let mut w = 0;
+                for (row, h) in rows.iter().zip(&heights) {
+                    w = w.max(self.nested(*h).layout_row(&row.ops, None, &opts.h_align)?.1);
+                }
+                opts.clamp(w)
+            }
+        };
+
+        // Lay out rows across the stack width
+        let mut stack = self.nested(self.cfg.y);
+        let mut y = 0;
+
+        for (row, height) in rows.iter().zip(heights) {
+            if height > 0 {
+                let (r, _) =
+                    self.nested(height)
+                        .layout_row(&row.ops, Some(width), &opts.h_align)?;
+                stack.place(&r, Point::new(0, y as i32), width)?;
+            }
+
+            y += height;
+        }
+
+        Ok((stack, width))
+    }
+
+    /// Lay out operations left to right within an (optional) width, sharing
+    /// remaining width between weighted items or aligning the content,
+    /// returning the nested render and row width
+    fn layout_row(
+        &self,
+        ops: &[Op],
+        width: Option<usize>,
+        align: &HAlign,
+    ) -> Result<(Render, usize), Error> {
+        let y = self.cfg.y;
+
+        // Render fixed items, collecting weighted items
+        let mut items = vec![];
+        for op in ops {
+            let opts = match op {
+                Op::Group { opts, .. } | Op::Stack { opts, .. } => Some(opts),
+                _ => None,
+            };
+
+            let item = match (op, opts.and_then(|o| o.width)) {
+                (Op::Spacer { size }, _) => match size {
+                    Length::Weight(weight) => Item::Flex {
+                        weight: *weight,
+                        min: 0,
+                        op,
+                    },
+                    _ => Item::Rendered(self.nested(y), size.pixels().unwrap_or(0)),
+                },
+                // Weighted containers are sized to their content in unsized rows
+                (_, Some(Length::Weight(weight))) if width.is_some() => Item::Flex {
+                    weight,
+                    min: opts.map(|o| o.clamp(0)).unwrap_or(0),
+                    op,
+                },
+                _ => {
+                    let (r, w) = self.render_container(op, None)?;
+                    Item::Rendered(r, w)
+                }
+            };
+
+            items.push(item);
+        }
+
+        // Share remaining width between weighted items
+        let fixed: usize = items
+            .iter()
+            .map(|i| match i {
+                Item::Rendered(_, w) => *w,
+                Item::Flex { min, .. } => *min,
+            })
+            .sum();
+        let weights: Vec<_> = items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Flex { weight, .. } => Some(Length::Weight(*weight)),
+                _ => None,
+            })
+            .collect();
+        let remaining = width.map(|w| w.saturating_sub(fixed)).unwrap_or(0);
+        let mut shares = Length::distribute(&weights, remaining).into_iter();
+
+        let mut placed = vec![];
+        for item in items {
+            placed.push(match item {
+                Item::Rendered(r, w) => (r, w),
+                Item::Flex { min, op, .. } => {
+                    let w = min + shares.next().unwrap_or(0);
+                    match op {
+                        Op::Spacer { .. } => (self.nested(y), w),
+                        _ => self.render_container(op, Some(w))?,
+                    }
+                }
+            });
+        }
+
+        // Align content within the row
+        let total: usize = placed.iter().map(|(_, w)| *w).sum();
+        let (width, offset) = match width {
+            Some(w) if total < w => match align {
+                HAlign::Left => (w, 0),
+                HAlign::Centre => (w, (w - total) / 2),
+                HAlign::Right => (w, w - total),
+            },
+            Some(w) => (w, 0),
+            None => (total, 0),
+        };
+
+        // Place items, clipped to the row width
+        let mut row = self.nested(y);
+        let mut x = offset;
+        for (r, w) in placed {
+            if x >= width {
+                break;
+            }
+
+            row.place(&r, Point::new(x as i32, 0), w.min(width - x))?;
+            x += w;
+        }
+
+        Ok((row, width))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render::{FontKind, RenderTemplate};
+
+    #[test]
+    fn test_length() {
+        assert_eq!("12".parse(), Ok(Length::Px(12)));
+        assert_eq!("2mm".parse(), Ok(Length::Mm(2.0)));
+        assert_eq!("3fr".parse(), Ok(Length::Weight(3)));
+        assert!("0fr".parse::<Length>().is_err());
+
+        // Fixed lengths first, with weights sharing the remainder
+        let l = [Length::Px(10), Length::Weight(1), Length::Weight(2)];
+        assert_eq!(Length::distribute(&l, 64), vec![10, 18, 36]);
+
+        // Fixed lengths are clipped to the total
+        let l = [Length::Px(48), Length::Mm(4.0), Length::Weight(1)];
+        assert_eq!(Length::distribute(&l, 64), vec![48, 16, 0]);
+    }
+
+    #[test]
+    fn test_stack() {
+        let wide = vec![Op::pad(4), Op::text_with_font("ABCD", FontKind::Font6x8)];
+        let ops = [Op::stack(vec![
+            Row::new(
+                Length::Px(16),
+                vec![Op::text_with_font("AB", FontKind::Font6x8)],
+            ),
+            Row::new(Length::Weight(1), wide.clone()),
+        ])];
+
+        let cfg = RenderConfig {
+            min_x: 0,
+            ..Default::default()
+        };
+        let mut r = Render::new(cfg.clone());
+        let w = r.render_ops(&ops).unwrap();
+
+        // Stack is as wide as the widest row, including trailing blank columns
+        assert_eq!(w, Render::new(cfg.clone()).render_ops(&wide).unwrap());
+        assert_eq!(r.display.populated_size().width as usize, w);
+
+        // Rows are placed within their share of the label height
+        let cols = r.display.populated_size().width as usize;
+        let set = |y0, y1| (0..cols).any(|x| (y0..y1).any(|y| r.display.get(x, y).unwrap()));
+        assert!(set(0, 16) && set(16, 64));
+        assert!(!(0..4).any(|x| (16..64).any(|y| r.display.get(x, y).unwrap())));
+
+        // Trailing padding within a row is kept when the row is placed
+        let padded = vec![Op::text_with_font("AB", FontKind::Font6x8), Op::pad(20)];
+        let ops = [Op::stack(vec![Row::new(Length::Weight(1), padded.clone())])];
+
+        let mut r = Render::new(cfg.clone());
+        let w = r.render_ops(&ops).unwrap();
+        assert_eq!(w, Render::new(cfg).render_ops(&padded).unwrap());
+        assert_eq!(r.display.populated_size().width as usize, w);
+    }
+
+    #[test]
+    fn test_group() {
+        let text = || Op::text_with_font("AB", FontKind::Font6x8);
+        let render = |op: Op| {
+            let mut r = Render::new(RenderConfig {
+                min_x: 0,
+                ..Default::default()
+            });
+            let w = r.render_ops(&[op]).unwrap();
+
+            // Columns containing set pixels
+            let set: Vec<_> = (0..r.display.populated_size().width as usize)
+                .filter(|x| (0..64).any(|y| r.display.get(*x, y).unwrap()))
+                .collect();
+
+            (w, set[0], set[set.len() - 1])
+        };
+
+        let (_, _, text_end) = render(text());
+
+        // Fixed width groups align content
+        let opts = GroupOptions {
+            width: Some(Length::Px(100)),
+            h_align: HAlign::Right,
+            ..Default::default()
+        };
+        let (w, first, _) = render(Op::group(vec![text()], opts.clone()));
+        assert_eq!(w, 100);
+        assert!(first > 50);
+
+        // Weighted spacers share the remaining width
+        let ops = vec![text(), Op::spacer(Length::Weight(1)), text()];
+        let (w, first, last) = render(Op::group(ops, opts));
+        assert_eq!(w, 100);
+        assert_eq!(first, 0);
+        assert!(last > 90);
+
+        // Content is clipped to the maximum width
+        let opts = GroupOptions {
+            max_width: Some(Length::Px(4)),
+            ..Default::default()
+        };
+        let (w, _, last) = render(Op::group(vec![text()], opts));
+        assert_eq!(w, 4);
+        assert!(last < 4 && last < text_end);
+
+        // Minimum width pads content, extending the label
+        let opts = GroupOptions {
+            min_width: Some(Length::Px(50)),
+            ..Default::default()
+        };
+        let op = Op::group(vec![text()], opts);
+        assert_eq!(render(op.clone()).0, 50);
+
+        let mut r = Render::new(RenderConfig {
+            min_x: 0,
+            ..Default::default()
+        });
+        r.render_ops(&[op]).unwrap();
+        assert_eq!(r.display.populated_size().width, 50);
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_stack_template() {
+        let t: RenderTemplate = toml::from_str(
+            r#"
+            [[ops]]
+            kind = "qr"
+            code = "SN-1234"
+
+            [[ops]]
+            kind = "stack"
+
+            [[ops.rows]]
+            size = "2fr"
+            ops = [{ kind = "text", text = "Name", fit = true }]
+
+            [[ops.rows]]
+            size = "3mm"
+            ops = [{ kind = "text", text = "SN-1234", font = "font6x8" }]
+            "#,
+        )
+        .unwrap();
+
+        match &t.ops[1] {
+            Op::Stack { rows, .. } => {
+                assert_eq!(rows[0].size, Length::Weight(2));
+                assert_eq!(rows[1].size, Length::Mm(3.0));
+                assert_eq!(rows[1].ops.len(), 1);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
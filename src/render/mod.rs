@@ -17,17 +17,9 @@ use log::debug;
 use clap::Args;
 
 use embedded_graphics::prelude::*;
-use embedded_text::{
-    alignment::{HorizontalAlignment, VerticalAlignment},
-    style::{HeightMode, TextBoxStyleBuilder, VerticalOverdraw},
-    TextBox,
-};
-
 use embedded_graphics::{
-    mono_font::MonoTextStyle,
     pixelcolor::BinaryColor,
     primitives::{PrimitiveStyle, Rectangle},
-    text::LineHeight,
 };
 
 #[cfg(feature = "preview")]
@@ -37,10 +29,15 @@ use embedded_graphics_simulator::{
 
 use crate::Error;
 
+pub mod bitmap;
+pub use bitmap::*;
 pub mod display;
 pub use display::*;
+pub mod layout;
 pub mod ops;
 pub use ops::*;
+pub mod text;
+pub use text::*;
 
 #[derive(Clone, PartialEq, Debug)]
 #[cfg_attr(feature = "clap", derive(Args))]
@@ -51,6 +48,9 @@ pub struct RenderConfig {
     pub max_x: usize,
     /// Image Y size
     pub y: usize,
+    #[cfg_attr(feature = "clap", arg(long, default_value = "landscape"))]
+    /// Label orientation
+    pub orientation: Orientation,
 }
 
 impl Default for RenderConfig {
@@ -59,6 +59,35 @@ impl Default for RenderConfig {
             min_x: 32,
             max_x: 10 * 1024,
             y: 64,
+            orientation: Orientation::Landscape,
+        }
+    }
+}
+
+/// Label orientation
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "strum",
+    derive(strum_macros::Display, strum_macros::EnumString)
+)]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[cfg_attr(feature = "strum", strum(serialize_all = "snake_case"))]
+pub enum Orientation {
+    /// Label is read along the tape
+    #[default]
+    Landscape,
+    /// Label is read with the tape running vertically, operations are laid out
+    /// top to bottom across the tape
+    Portrait,
+}
+
+impl Orientation {
+    /// Fetch the rotation applied to operations to lay them out on the tape
+    pub fn rotation(&self) -> Rotation {
+        match self {
+            Orientation::Landscape => Rotation::None,
+            Orientation::Portrait => Rotation::Cw270,
         }
     }
 }
@@ -72,16 +101,22 @@ impl Render {
     /// Create a new render instance
     pub fn new(cfg: RenderConfig) -> Self {
         // Setup virtual display for render data
-        let display = Display::new(cfg.y as usize, cfg.min_x as usize);
+        let mut display = Display::new(cfg.y, cfg.min_x);
+        display.set_orientation(cfg.orientation);
 
         // Return new renderer
         Self { cfg, display }
     }
 
-    /// Save the render buffer as an image
+    /// Fetch the rendered size (along and across the tape)
+    pub fn size(&self) -> Size {
+        self.display.populated_size()
+    }
+
+    /// Save the render buffer as an image (as the label is read)
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), anyhow::Error> {
         // Fetch current display size
-        let size = self.display.populated_size();
+        let size = self.display.view_size();
 
         // Create image
         let i = image::DynamicImage::new_luma8(size.width, size.height);
@@ -90,7 +125,7 @@ impl Render {
         // Copy data into image
         for x in 0..size.width {
             for y in 0..size.height {
-                let p = self.display.get(x as usize, y as usize)?;
+                let p = self.display.get_view(x as usize, y as usize)?;
                 if !p {
                     i.put_pixel(x, y, Luma([0xff]));
                 }
@@ -105,6 +140,15 @@ impl Render {
 
     /// Execute render operations
     pub fn render(&mut self, ops: &[Op]) -> Result<&Self, Error> {
+        self.render_ops(ops)?;
+
+        // TODO: store data? idk
+
+        Ok(self)
+    }
+
+    /// Execute render operations left to right, returning the rendered width
+    fn render_ops(&mut self, ops: &[Op]) -> Result<usize, Error> {
         let mut x = 0;
         for operation in ops {
             x += match operation {
@@ -114,12 +158,16 @@ impl Render {
                 Op::DataMatrix { code } => self.render_datamatrix(x, code)?,
                 Op::Barcode { code, opts } => self.render_barcode(x, code, opts)?,
                 Op::Image { file, opts } => self.render_image(x, file, opts)?,
+                Op::Stack { .. } | Op::Group { .. } => {
+                    let (r, w) = self.render_container(operation, None)?;
+                    self.place(&r, Point::new(x as i32, 0), w)?;
+                    w
+                }
+                Op::Spacer { size } => self.pad(x, size.pixels().unwrap_or(0))?,
             }
         }
 
-        // TODO: store data? idk
-
-        Ok(self)
+        Ok(x)
     }
 
     fn render_text(
@@ -128,51 +176,103 @@ impl Render {
         value: &str,
         opts: &TextOptions,
     ) -> Result<usize, Error> {
-        // TODO: custom alignment
-
         // Fix for escaped newlines from shell
         // Otherwise "\n" becomes "\\n" and nothing works quite right
         let value = value.replace("\\n", "\n");
 
-        // Compute maximum line width
-        let max_line_x = value
-            .split("\n")
-            .map(|line| opts.font.char_width() * line.len() + 1)
-            .max()
-            .unwrap();
-        let max_x = self.cfg.max_x.min(start_x + max_line_x);
-
-        // Create textbox instance
-        let bounds = Rectangle::new(
-            Point::new(start_x as i32, 0),
-            Size::new((max_x - start_x) as u32, self.cfg.y as u32),
-        );
-
-        let h_a = match opts.h_align {
-            HAlign::Centre => HorizontalAlignment::Center,
-            HAlign::Left => HorizontalAlignment::Left,
-            HAlign::Right => HorizontalAlignment::Right,
+        // Text is rotated with the label orientation, rotated text is laid out across
+        // the label (and fitted within a square of the label height)
+        let rotation = opts.rotation.then(self.cfg.orientation.rotation());
+        let transposed = rotation.is_transposed();
+
+        // Load fonts and break lines (fitting to the label if enabled), then compute
+        // line sizes from font metrics
+        let (font, lines) =
+            FontChain::layout(opts, &value, self.cfg.y, transposed.then_some(self.cfg.y))?;
+        let widths = lines
+            .iter()
+            .map(|l| font.width(l))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Inverse text is padded within the box
+        let pad = match opts.inverse {
+            true => font.primary().height() / 4,
+            false => 0,
         };
 
-        let v_a = match opts.v_align {
-            VAlign::Centre => VerticalAlignment::Middle,
-            VAlign::Top => VerticalAlignment::Top,
-            VAlign::Bottom => VerticalAlignment::Bottom,
+        let text_height = font.text_height(lines.len());
+
+        // Compute text box size (prior to rotation)
+        let size = match transposed {
+            false => {
+                let max_line_x = widths.iter().max().copied().unwrap_or(0) + 1 + pad * 2;
+                let max_x = self.cfg.max_x.min(start_x + max_line_x);
+                Size::new((max_x - start_x) as u32, self.cfg.y as u32)
+            }
+            true => Size::new(self.cfg.y as u32, (text_height + pad * 2) as u32),
         };
+        let (box_width, box_height) = (size.width as i32, size.height as i32);
 
-        let character_style = MonoTextStyle::new(opts.font.font(), BinaryColor::On);
-        let textbox_style = TextBoxStyleBuilder::new()
-            .height_mode(HeightMode::Exact(VerticalOverdraw::Hidden))
-            .alignment(h_a)
-            .vertical_alignment(v_a)
-            .line_height(LineHeight::Pixels(opts.font.char_height() as u32 + 4))
-            .build();
+        // Draw into a frame clipped to the text box, which is then rotated onto the label
+        let mut frame = Display::new(size.height as usize, size.width as usize);
+        let bounds = Rectangle::new(Point::zero(), size);
+        let mut display = frame.clipped(&bounds);
+
+        if opts.inverse {
+            bounds
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                .draw(&mut display)?;
+        }
+
+        let pad = pad as i32;
+        let mut y = match opts.v_align {
+            VAlign::Top => pad,
+            VAlign::Centre => (box_height - text_height as i32) / 2,
+            VAlign::Bottom => box_height - text_height as i32 - pad,
+        };
+
+        for (line, width) in lines.iter().zip(widths.iter()) {
+            let x = match opts.h_align {
+                HAlign::Left => pad,
+                HAlign::Centre => (box_width - *width as i32) / 2,
+                HAlign::Right => box_width - *width as i32 - pad,
+            };
 
-        let tb = TextBox::with_textbox_style(&value, bounds, character_style, textbox_style);
+            font.draw(&mut display, Point::new(x, y), line)?;
 
-        tb.draw(&mut self.display).unwrap();
+            y += font.line_height() as i32;
+        }
+
+        self.blit(&frame, size, Point::new(start_x as i32, 0), rotation)?;
 
-        Ok(tb.bounding_box().size.width as usize)
+        Ok(rotation.size(size).width as usize)
+    }
+
+    /// Copy set pixels from a frame of the provided size onto the label,
+    /// rotating the frame and placing it at `origin` (clipped to the label height)
+    fn blit(
+        &mut self,
+        frame: &Display,
+        size: Size,
+        origin: Point,
+        rotation: Rotation,
+    ) -> Result<(), Error> {
+        for x in 0..size.width as usize {
+            for y in 0..size.height as usize {
+                if !frame.get(x, y)? {
+                    continue;
+                }
+
+                let p = origin + rotation.transform(Point::new(x as i32, y as i32), size);
+                if p.x < 0 || p.y < 0 || p.y as usize >= self.cfg.y {
+                    continue;
+                }
+
+                self.display.draw_pixel(Pixel(p, BinaryColor::On))?;
+            }
+        }
+
+        Ok(())
     }
 
     fn pad(&mut self, x: usize, columns: usize) -> Result<usize, Error> {
@@ -192,21 +292,26 @@ impl Render {
             .max_dimensions(self.cfg.y as u32, self.cfg.y as u32)
             .build();
 
-        // Generate offsets
-        let y_offset = (self.cfg.y as i32 - img.height() as i32) / 2;
-        let x_offset = x_start as i32 + y_offset;
+        // Copy code into a frame
+        let size = Size::new(img.width(), img.height());
+        let mut frame = Display::new(img.height() as usize, img.width() as usize);
 
-        // Write to display
         for (x, y, v) in img.enumerate_pixels() {
-            let c = match v {
-                image::Rgb([0, 0, 0]) => BinaryColor::On,
-                _ => BinaryColor::Off,
-            };
-            let p = Pixel(Point::new(x_offset + x as i32, y_offset + y as i32), c);
-            self.display.draw_pixel(p)?
+            if let image::Rgb([0, 0, 0]) = v {
+                frame.set(x as usize, y as usize, true)?;
+            }
         }
 
-        Ok(img.width() as usize + x_offset as usize)
+        // Rotate onto the label with the label orientation
+        let rotation = self.cfg.orientation.rotation();
+        let rotated = rotation.size(size);
+
+        let y_offset = (self.cfg.y as i32 - rotated.height as i32) / 2;
+        let x_offset = x_start as i32 + y_offset;
+
+        self.blit(&frame, size, Point::new(x_offset, y_offset), rotation)?;
+
+        Ok(rotated.width as usize + x_offset as usize)
     }
 
     fn render_datamatrix(&mut self, x_start: usize, value: &str) -> Result<usize, Error> {
@@ -218,20 +323,35 @@ impl Render {
         // We want to make the datamatrix as large as possible for scanning
         let scale = self.cfg.y / bitmap.height();
 
-        let x_offset = x_start;
-        let y_offset = ((self.cfg.y as i32 - (bitmap.height() * scale) as i32) / 2) as usize;
+        // Copy scaled code into a frame
+        let (w, h) = (bitmap.width() * scale, bitmap.height() * scale);
+        let size = Size::new(w as u32, h as u32);
+        let mut frame = Display::new(h, w);
 
         for (x, y) in bitmap.pixels() {
-            let xs = x_offset + x * scale;
-            let ys = y_offset + y * scale;
             let r = Rectangle::new(
-                Point::new(xs as i32, ys as i32),
+                Point::new((x * scale) as i32, (y * scale) as i32),
                 Size::new(scale as u32, scale as u32),
             );
             r.into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
-                .draw(&mut self.display)?;
+                .draw(&mut frame)?;
         }
-        Ok(bitmap.width() * scale + x_offset)
+
+        // Rotate onto the label with the label orientation
+        let rotation = self.cfg.orientation.rotation();
+        let rotated = rotation.size(size);
+
+        let x_offset = x_start;
+        let y_offset = (self.cfg.y as i32 - rotated.height as i32) / 2;
+
+        self.blit(
+            &frame,
+            size,
+            Point::new(x_offset as i32, y_offset),
+            rotation,
+        )?;
+
+        Ok(rotated.width as usize + x_offset)
     }
 
     fn render_barcode(
@@ -243,24 +363,30 @@ impl Render {
         let barcode = Code39::new(value).unwrap();
         let encoded: Vec<u8> = barcode.encode();
 
-        let x_offset = x_start as i32;
+        // Copy bars into a frame
+        let h = self.cfg.y.saturating_sub(opts.y_offset * 2);
+        let size = Size::new(encoded.len() as u32, h as u32);
+        let mut frame = Display::new(h, encoded.len());
 
         // TODO: something is not quite right here...
         for i in 0..encoded.len() {
             //let v = (encoded[i / 8] & ( 1 << (i % 8) ) ) == 0;
 
-            for y in opts.y_offset..self.cfg.y - opts.y_offset {
-                let c = match encoded[i] != 0 {
-                    true => BinaryColor::On,
-                    false => BinaryColor::Off,
-                };
-
-                let p = Pixel(Point::new(x_offset + i as i32, y as i32), c);
-                self.display.draw_pixel(p)?
+            for y in 0..h {
+                frame.set(i, y, encoded[i] != 0)?;
             }
         }
 
-        Ok(encoded.len() + x_offset as usize)
+        // Rotate onto the label with the label orientation
+        let rotation = self.cfg.orientation.rotation();
+        let rotated = rotation.size(size);
+
+        let x_offset = x_start as i32;
+        let y_offset = (self.cfg.y as i32 - rotated.height as i32) / 2;
+
+        self.blit(&frame, size, Point::new(x_offset, y_offset), rotation)?;
+
+        Ok(rotated.width as usize + x_offset as usize)
     }
 
     fn render_image(
@@ -276,25 +402,29 @@ impl Render {
 
         // TODO: Rescale based on image options
 
-        let x_offset = x_start as i32;
-        let y_offset = (self.cfg.y / 2) as i32 - (d.1 as usize / 2) as i32;
-
-        // Copy image data into display
-        for x in 0..d.0 as i32 {
-            for y in 0..d.1 as i32 {
-                let p = i.get_pixel(x as u32, y as u32);
+        // Copy image data into a frame
+        let size = Size::new(d.0, d.1);
+        let mut frame = Display::new(d.1 as usize, d.0 as usize);
 
-                let c = match p.0[0] == 0 {
-                    true => BinaryColor::On,
-                    false => BinaryColor::Off,
-                };
-
-                let p = Pixel(Point::new(x_offset + x as i32, y_offset + y as i32), c);
-                self.display.draw_pixel(p)?
+        for x in 0..d.0 {
+            for y in 0..d.1 {
+                let p = i.get_pixel(x, y);
+                if p.0[0] == 0 {
+                    frame.set(x as usize, y as usize, true)?;
+                }
             }
         }
 
-        Ok(d.0 as usize + x_offset as usize)
+        // Rotate onto the label with the label orientation
+        let rotation = self.cfg.orientation.rotation();
+        let rotated = rotation.size(size);
+
+        let x_offset = x_start as i32;
+        let y_offset = (self.cfg.y as i32 - rotated.height as i32) / 2;
+
+        self.blit(&frame, size, Point::new(x_offset, y_offset), rotation)?;
+
+        Ok(rotated.width as usize + x_offset as usize)
     }
 
     /// Raster data to a ptouch compatible buffer for printing
@@ -306,7 +436,7 @@ impl Render {
     #[cfg(feature = "preview")]
     pub fn show(&self) -> Result<(), anyhow::Error> {
         // Fetch rendered size
-        let s = self.display.populated_size();
+        let s = self.display.view_size();
 
         debug!("Render display size: {:?}", s);
 
@@ -316,8 +446,11 @@ impl Render {
         // Copy buffer into simulated display
         for y in 0..s.height as usize {
             for x in 0..s.width as usize {
-                let p = self.display.get_pixel(x, y)?;
-                p.draw(&mut sim_display)?;
+                let c = match self.display.get_view(x, y)? {
+                    true => BinaryColor::On,
+                    false => BinaryColor::Off,
+                };
+                Pixel(Point::new(x as i32, y as i32), c).draw(&mut sim_display)?;
             }
         }
 
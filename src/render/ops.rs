@@ -4,6 +4,10 @@
 // https://github.com/ryankurte/rust-ptouch
 // Copyright 2021 Ryan Kurte
 
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
 #[cfg(feature = "strum")]
 use strum_macros::{Display, EnumString, EnumVariantNames};
 
@@ -14,6 +18,7 @@ use serde::{Deserialize, Serialize};
 use clap::Args;
 
 use embedded_graphics::mono_font::{ascii::FONT_6X9, MonoFont};
+use embedded_graphics::prelude::{Point, Size};
 use embedded_vintage_fonts::{FONT_12X16, FONT_24X32, FONT_6X12, FONT_6X8, FONT_8X16};
 
 #[derive(Clone, Debug, PartialEq)]
@@ -51,6 +56,40 @@ pub enum Op {
         #[cfg_attr(feature = "serde", serde(flatten, default))]
         opts: ImageOptions,
     },
+    /// Rows stacked across the label height, each laid out left to right
+    Stack {
+        rows: Vec<Row>,
+        #[cfg_attr(feature = "serde", serde(flatten, default))]
+        opts: GroupOptions,
+    },
+    /// Operations grouped into a box laid out left to right
+    Group {
+        ops: Vec<Op>,
+        #[cfg_attr(feature = "serde", serde(flatten, default))]
+        opts: GroupOptions,
+    },
+    /// Blank space, weighted spacers share the width remaining in a sized group or stack
+    Spacer {
+        #[cfg_attr(feature = "serde", serde(default))]
+        size: Length,
+    },
+}
+
+/// Row within a [Op::Stack]
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Row {
+    /// Row height, weighted rows share the height remaining after fixed rows
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub size: Length,
+    /// Render operations for the row
+    pub ops: Vec<Op>,
+}
+
+impl Row {
+    pub fn new(size: Length, ops: Vec<Op>) -> Self {
+        Self { size, ops }
+    }
 }
 
 impl Op {
@@ -71,6 +110,13 @@ impl Op {
         }
     }
 
+    pub fn text_with_options(s: &str, opts: TextOptions) -> Self {
+        Self::Text {
+            text: s.to_string(),
+            opts,
+        }
+    }
+
     pub fn pad(columns: usize) -> Self {
         Self::Pad { count: columns }
     }
@@ -94,6 +140,21 @@ impl Op {
         }
     }
 
+    pub fn stack(rows: Vec<Row>) -> Self {
+        Self::Stack {
+            rows,
+            opts: GroupOptions::default(),
+        }
+    }
+
+    pub fn group(ops: Vec<Op>, opts: GroupOptions) -> Self {
+        Self::Group { ops, opts }
+    }
+
+    pub fn spacer(size: Length) -> Self {
+        Self::Spacer { size }
+    }
+
     pub fn image(file: &str) -> Self {
         Self::Image {
             file: file.to_string(),
@@ -122,6 +183,16 @@ pub enum FontKind {
 }
 
 impl FontKind {
+    /// All bitmap fonts
+    pub const ALL: &'static [FontKind] = &[
+        FontKind::Font6x6,
+        FontKind::Font6x8,
+        FontKind::Font6x12,
+        FontKind::Font8x16,
+        FontKind::Font12x16,
+        FontKind::Font24x32,
+    ];
+
     /// Fetch the embedded-graphics MonoFont matching this font kind.
     ///
     /// `Font6x8`/`Font6x12`/`Font8x16`/`Font12x16`/`Font24x32` use embedded-vintage-fonts,
@@ -147,11 +218,331 @@ impl FontKind {
     }
 }
 
+/// Scalable (TrueType / OpenType) font face
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String", into = "String"))]
+pub enum FontFace {
+    /// Embedded default font (DejaVu Sans)
+    Sans,
+    /// Embedded bold font (DejaVu Sans Bold)
+    SansBold,
+    /// Font loaded from a TTF / OTF file
+    File(String),
+    /// Bitmap font loaded from a BDF / PCF file
+    Bitmap(String),
+}
+
+impl FromStr for FontFace {
+    type Err = std::convert::Infallible;
+
+    /// Parse a font face, `sans` or `sans-bold` select the embedded fonts, otherwise a file path
+    /// (with `.bdf` and `.pcf` files loaded as bitmap fonts)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(FontFace::from(s.to_string()))
+    }
+}
+
+impl fmt::Display for FontFace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FontFace::Sans => write!(f, "sans"),
+            FontFace::SansBold => write!(f, "sans-bold"),
+            FontFace::File(p) | FontFace::Bitmap(p) => write!(f, "{}", p),
+        }
+    }
+}
+
+impl From<String> for FontFace {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "sans" => FontFace::Sans,
+            "sans-bold" => FontFace::SansBold,
+            _ if BITMAP_EXTENSIONS
+                .iter()
+                .any(|e| s.to_lowercase().ends_with(e)) =>
+            {
+                FontFace::Bitmap(s)
+            }
+            _ => FontFace::File(s),
+        }
+    }
+}
+
+impl From<FontFace> for String {
+    fn from(f: FontFace) -> Self {
+        f.to_string()
+    }
+}
+
+/// File extensions for bitmap font faces
+const BITMAP_EXTENSIONS: &[&str] = &[".bdf", ".pcf"];
+
+/// Print resolution in dots per inch, used to convert font sizes to pixels
+pub const DPI: f32 = 180.0;
+
+/// Font size for scalable fonts, parsed from `12pt`, `3.5mm` or `20px` (default)
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String", into = "String"))]
+pub enum FontSize {
+    /// Size in pixels (print head dots)
+    Px(f32),
+    /// Size in points
+    Pt(f32),
+    /// Size in millimetres
+    Mm(f32),
+}
+
+impl FontSize {
+    /// Fetch the font size in pixels
+    pub fn pixels(&self) -> f32 {
+        match self {
+            FontSize::Px(v) => *v,
+            FontSize::Pt(v) => *v * DPI / 72.0,
+            FontSize::Mm(v) => *v * DPI / 25.4,
+        }
+    }
+}
+
+impl Default for FontSize {
+    fn default() -> Self {
+        FontSize::Px(20.0)
+    }
+}
+
+impl FromStr for FontSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        let (v, f): (&str, fn(f32) -> FontSize) = if let Some(v) = s.strip_suffix("pt") {
+            (v, FontSize::Pt)
+        } else if let Some(v) = s.strip_suffix("mm") {
+            (v, FontSize::Mm)
+        } else if let Some(v) = s.strip_suffix("px") {
+            (v, FontSize::Px)
+        } else {
+            (s, FontSize::Px)
+        };
+
+        match v.trim().parse::<f32>() {
+            Ok(v) if v > 0.0 => Ok(f(v)),
+            _ => Err(format!(
+                "Invalid font size '{}', expected eg. 12pt, 3mm or 20px",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FontSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FontSize::Px(v) => write!(f, "{}px", v),
+            FontSize::Pt(v) => write!(f, "{}pt", v),
+            FontSize::Mm(v) => write!(f, "{}mm", v),
+        }
+    }
+}
+
+impl TryFrom<String> for FontSize {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<FontSize> for String {
+    fn from(s: FontSize) -> Self {
+        s.to_string()
+    }
+}
+
+/// Layout length, parsed from `20px`, `3mm` or a `2fr` weight sharing the
+/// length remaining after fixed lengths
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String", into = "String"))]
+pub enum Length {
+    /// Length in pixels (print head dots)
+    Px(usize),
+    /// Length in millimetres
+    Mm(f32),
+    /// Weighted share of the remaining length
+    Weight(u32),
+}
+
+impl Length {
+    /// Fetch fixed lengths in pixels (or `None` for weights)
+    pub fn pixels(&self) -> Option<usize> {
+        match self {
+            Length::Px(v) => Some(*v),
+            Length::Mm(v) => Some((*v * DPI / 25.4).round() as usize),
+            Length::Weight(_) => None,
+        }
+    }
+
+    /// Distribute a total length between lengths, fixed lengths are allocated
+    /// first (in order, until the total is used) with any remainder shared
+    /// between weights
+    pub fn distribute(lengths: &[Length], total: usize) -> Vec<usize> {
+        let mut remaining = total;
+        let mut sizes: Vec<_> = lengths
+            .iter()
+            .map(|l| match l.pixels() {
+                Some(v) => {
+                    let v = v.min(remaining);
+                    remaining -= v;
+                    v
+                }
+                None => 0,
+            })
+            .collect();
+
+        let weights: u32 = lengths
+            .iter()
+            .map(|l| match l {
+                Length::Weight(w) => *w,
+                _ => 0,
+            })
+            .sum();
+        if weights == 0 {
+            return sizes;
+        }
+
+        // Share the remainder by weight, with rounding error going to the last weights
+        let (mut acc, mut used) = (0, 0);
+        for (s, l) in sizes.iter_mut().zip(lengths) {
+            if let Length::Weight(w) = l {
+                acc += *w;
+                let end = remaining * acc as usize / weights as usize;
+                *s = end - used;
+                used = end;
+            }
+        }
+
+        sizes
+    }
+}
+
+impl Default for Length {
+    fn default() -> Self {
+        Length::Weight(1)
+    }
+}
+
+impl FromStr for Length {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let err = || format!("Invalid length '{}', expected eg. 20px, 3mm or 1fr", s);
+
+        if let Some(v) = s.strip_suffix("fr") {
+            return match v.trim().parse::<u32>() {
+                Ok(v) if v > 0 => Ok(Length::Weight(v)),
+                _ => Err(err()),
+            };
+        }
+
+        if let Some(v) = s.strip_suffix("mm") {
+            return match v.trim().parse::<f32>() {
+                Ok(v) if v >= 0.0 => Ok(Length::Mm(v)),
+                _ => Err(err()),
+            };
+        }
+
+        let v = s.strip_suffix("px").unwrap_or(s);
+        v.trim().parse().map(Length::Px).map_err(|_| err())
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Length::Px(v) => write!(f, "{}px", v),
+            Length::Mm(v) => write!(f, "{}mm", v),
+            Length::Weight(v) => write!(f, "{}fr", v),
+        }
+    }
+}
+
+impl TryFrom<String> for Length {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Length> for String {
+    fn from(s: Length) -> Self {
+        s.to_string()
+    }
+}
+
+/// Behaviour for characters that can not be rendered with any font
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "strum", derive(Display, EnumString, EnumVariantNames))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[cfg_attr(feature = "strum", strum(serialize_all = "snake_case"))]
+pub enum MissingGlyph {
+    /// Render a replacement glyph
+    #[default]
+    Replace,
+    /// Fail rendering with an error
+    Error,
+}
+
 #[derive(Clone, PartialEq, Debug)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[cfg_attr(feature = "serde", serde(default))]
 pub struct TextOptions {
+    /// Bitmap font, used where no scalable `face` is set
     pub font: FontKind,
+    /// Scalable (or bitmap file) font face, overriding the bitmap `font`
+    pub face: Option<FontFace>,
+    /// Integer scale for bitmap fonts
+    pub scale: u32,
+    /// Scalable font size
+    pub size: FontSize,
+    /// Coverage threshold (0-255) above which scalable font pixels are set
+    pub threshold: u8,
+    /// Pick the largest font size (or bitmap font) where all lines fit the label height,
+    /// overriding `size` / `font`
+    pub fit: bool,
+    /// Minimum font size when fitting text
+    pub min_size: Option<FontSize>,
+    /// Maximum font size when fitting text
+    pub max_size: Option<FontSize>,
+    /// Scalable fallback fonts for characters not covered by the primary font,
+    /// the embedded font is always used as a final fallback
+    pub fallback: Vec<FontFace>,
+    /// Behaviour for characters not covered by any font
+    pub missing: MissingGlyph,
+    /// Bold text, using `bold_face` (or the embedded bold font for `sans`) where available,
+    /// otherwise glyphs are emboldened (synthetic bold)
+    pub bold: bool,
+    /// Scalable font face for bold text, used only where `face` is also scalable
+    pub bold_face: Option<FontFace>,
+    /// Underline text
+    pub underline: bool,
+    /// Strike through text
+    pub strikethrough: bool,
+    /// Inverse text, drawn in white on a black box
+    pub inverse: bool,
+    /// Additional spacing between characters in pixels (may be negative)
+    pub letter_spacing: i32,
+    /// Spacing between lines in pixels (may be negative), overriding the font default
+    pub line_spacing: Option<i32>,
+    /// Text rotation (clockwise), rotated text is laid out across the label
+    pub rotation: Rotation,
+    /// Automatic line breaking, in addition to explicit newlines
+    pub wrap: Wrap,
     pub v_align: VAlign,
     pub h_align: HAlign,
 }
@@ -186,10 +577,195 @@ impl Default for VAlign {
     }
 }
 
+/// Clockwise rotation, parsed from degrees (`0`, `90`, `180` or `270`)
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "u16", into = "u16"))]
+pub enum Rotation {
+    #[default]
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl Rotation {
+    /// Fetch the rotation in degrees
+    pub fn degrees(&self) -> u16 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Cw90 => 90,
+            Rotation::Cw180 => 180,
+            Rotation::Cw270 => 270,
+        }
+    }
+
+    /// Combine with a further rotation
+    pub fn then(&self, r: Rotation) -> Rotation {
+        // Sum of valid rotations is always valid
+        Rotation::try_from((self.degrees() + r.degrees()) % 360).unwrap()
+    }
+
+    /// Check whether the rotation swaps width and height
+    pub fn is_transposed(&self) -> bool {
+        matches!(self, Rotation::Cw90 | Rotation::Cw270)
+    }
+
+    /// Compute the size of an area after rotation
+    pub fn size(&self, size: Size) -> Size {
+        match self.is_transposed() {
+            true => Size::new(size.height, size.width),
+            false => size,
+        }
+    }
+
+    /// Rotate a point within an area of the provided size, returning the point
+    /// relative to the top-left of the rotated area
+    pub fn transform(&self, p: Point, size: Size) -> Point {
+        let (w, h) = (size.width as i32, size.height as i32);
+
+        match self {
+            Rotation::None => p,
+            Rotation::Cw90 => Point::new(h - 1 - p.y, p.x),
+            Rotation::Cw180 => Point::new(w - 1 - p.x, h - 1 - p.y),
+            Rotation::Cw270 => Point::new(p.y, w - 1 - p.x),
+        }
+    }
+}
+
+impl TryFrom<u16> for Rotation {
+    type Error = String;
+
+    fn try_from(v: u16) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(Rotation::None),
+            90 => Ok(Rotation::Cw90),
+            180 => Ok(Rotation::Cw180),
+            270 => Ok(Rotation::Cw270),
+            _ => Err(format!(
+                "Invalid rotation '{}', expected 0, 90, 180 or 270",
+                v
+            )),
+        }
+    }
+}
+
+impl From<Rotation> for u16 {
+    fn from(r: Rotation) -> Self {
+        r.degrees()
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid rotation '{}', expected degrees", s))?;
+        Rotation::try_from(v)
+    }
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.degrees())
+    }
+}
+
+/// Automatic line breaking, parsed from `none`, `auto` or a number of lines
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "WrapValue", into = "String"))]
+pub enum Wrap {
+    /// Break lines at explicit newlines only
+    #[default]
+    None,
+    /// Break into as many lines as fit the label at the configured font size
+    /// (or with the font fitted to each line count), picking the line count
+    /// resulting in the shortest label
+    Auto,
+    /// Break into (up to) the provided number of lines with balanced widths
+    Lines(usize),
+}
+
+impl FromStr for Wrap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(Wrap::None),
+            "auto" => Ok(Wrap::Auto),
+            v => match v.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(Wrap::Lines(n)),
+                _ => Err(format!(
+                    "Invalid wrap '{}', expected none, auto or a number of lines",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Wrap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Wrap::None => write!(f, "none"),
+            Wrap::Auto => write!(f, "auto"),
+            Wrap::Lines(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// Serialised wrap value, allowing line counts as numbers or strings
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WrapValue {
+    Lines(usize),
+    Str(String),
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<WrapValue> for Wrap {
+    type Error = String;
+
+    fn try_from(v: WrapValue) -> Result<Self, Self::Error> {
+        match v {
+            WrapValue::Lines(n) => format!("{}", n).parse(),
+            WrapValue::Str(s) => s.parse(),
+        }
+    }
+}
+
+impl From<Wrap> for String {
+    fn from(w: Wrap) -> Self {
+        w.to_string()
+    }
+}
+
 impl Default for TextOptions {
     fn default() -> Self {
         Self {
             font: FontKind::Font12x16,
+            face: None,
+            scale: 1,
+            size: FontSize::default(),
+            threshold: 128,
+            fit: false,
+            min_size: None,
+            max_size: None,
+            fallback: vec![],
+            missing: MissingGlyph::Replace,
+            bold: false,
+            bold_face: None,
+            underline: false,
+            strikethrough: false,
+            inverse: false,
+            letter_spacing: 0,
+            line_spacing: None,
+            rotation: Rotation::None,
+            wrap: Wrap::None,
             h_align: HAlign::Centre,
             v_align: VAlign::Centre,
         }
@@ -209,6 +785,46 @@ pub struct BarcodeOptions {
     pub double: bool,
 }
 
+/// Layout options for containers ([Op::Group] and [Op::Stack])
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct GroupOptions {
+    /// Container width, otherwise sized to the content. Weighted widths share the
+    /// width remaining in the parent container
+    pub width: Option<Length>,
+    /// Minimum container width
+    pub min_width: Option<Length>,
+    /// Maximum container width, content is clipped to this width
+    pub max_width: Option<Length>,
+    /// Content alignment when narrower than the container (and there are no weighted spacers)
+    pub h_align: HAlign,
+}
+
+impl GroupOptions {
+    /// Clamp a width to the minimum and maximum container widths
+    pub fn clamp(&self, width: usize) -> usize {
+        let min = self.min_width.and_then(|l| l.pixels()).unwrap_or(0);
+        let max = self
+            .max_width
+            .and_then(|l| l.pixels())
+            .unwrap_or(usize::MAX);
+
+        width.max(min).min(max)
+    }
+}
+
+impl Default for GroupOptions {
+    fn default() -> Self {
+        Self {
+            width: None,
+            min_width: None,
+            max_width: None,
+            h_align: HAlign::Left,
+        }
+    }
+}
+
 impl Default for BarcodeOptions {
     fn default() -> Self {
         Self {
@@ -0,0 +1,1351 @@
+//! Text layout and font rasterisation
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use ab_glyph::{point, Font as _, FontRef, GlyphId, PxScale, ScaleFont};
+use ouroboros::self_referencing;
+use rustybuzz::{Direction, UnicodeBuffer};
+
+use embedded_graphics::{
+    mono_font::{MonoFont, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+
+use log::debug;
+use unicode_bidi::BidiInfo;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::{BitmapFont, FontFace, FontKind, MissingGlyph, TextOptions, Wrap};
+use crate::Error;
+
+/// Embedded default scalable font (DejaVu Sans, see `fonts/LICENSE-DejaVu`)
+pub const DEFAULT_FONT: &[u8] = include_bytes!("../../fonts/DejaVuSans.ttf");
+
+/// Embedded bold scalable font (DejaVu Sans Bold, see `fonts/LICENSE-DejaVu`)
+pub const DEFAULT_BOLD_FONT: &[u8] = include_bytes!("../../fonts/DejaVuSans-Bold.ttf");
+
+/// Additional spacing between lines for bitmap fonts
+const MONO_LINE_SPACING: usize = 4;
+
+/// Maximum scale for loaded bitmap fonts when fitting text
+const MAX_BITMAP_SCALE: u32 = 8;
+
+/// Default minimum size when fitting text
+const MIN_FIT_PX: f32 = 6.0;
+
+/// Precision for the scalable font size search when fitting text
+const FIT_PRECISION_PX: f32 = 0.25;
+
+/// Replacement character for graphemes that can not be rendered
+const REPLACEMENT: &str = "\u{FFFD}";
+
+/// Font height divisor for synthetic bold weight
+const SYNTHETIC_BOLD_DIV: u32 = 16;
+
+/// Font height divisor for underline / strikethrough thickness
+const DECORATION_DIV: usize = 12;
+
+/// Scalable font data, parsed once and shared between rasterisation (ab_glyph)
+/// and shaping (rustybuzz)
+#[self_referencing]
+pub struct ScalableFont {
+    data: Cow<'static, [u8]>,
+    #[borrows(data)]
+    #[covariant]
+    font: FontRef<'this>,
+    #[borrows(data)]
+    #[covariant]
+    shaper: Option<rustybuzz::Face<'this>>,
+}
+
+impl ScalableFont {
+    /// Parse font data
+    pub fn parse(data: Cow<'static, [u8]>) -> Result<Self, Error> {
+        ScalableFontTryBuilder {
+            data,
+            font_builder: |d| FontRef::try_from_slice(d).map_err(|e| Error::Font(e.to_string())),
+            shaper_builder: |d| Ok(rustybuzz::Face::from_slice(d, 0)),
+        }
+        .try_build()
+    }
+
+    /// Fetch the font for glyph lookup and rasterisation
+    pub fn font(&self) -> &FontRef<'_> {
+        self.borrow_font()
+    }
+
+    /// Fetch the face used for shaping (if the font could be parsed for shaping)
+    fn shaper(&self) -> Option<&rustybuzz::Face<'_>> {
+        self.borrow_shaper().as_ref()
+    }
+}
+
+/// Fetch a parsed embedded font, parsing on first use
+fn embedded(cell: &'static OnceLock<Arc<ScalableFont>>, data: &'static [u8]) -> Arc<ScalableFont> {
+    cell.get_or_init(|| {
+        Arc::new(ScalableFont::parse(Cow::Borrowed(data)).expect("invalid embedded font"))
+    })
+    .clone()
+}
+
+/// Fonts loaded from files, keyed by path
+type FontCache<T> = OnceLock<Mutex<HashMap<String, Arc<T>>>>;
+
+static SCALABLE_FILES: FontCache<ScalableFont> = OnceLock::new();
+static BITMAP_FILES: FontCache<BitmapFont> = OnceLock::new();
+
+/// Fetch a font loaded from a file, loading on first use so fonts are read
+/// and parsed once per process rather than for each text operation
+fn cached<T>(
+    cache: &'static FontCache<T>,
+    path: &str,
+    load: impl FnOnce() -> Result<T, Error>,
+) -> Result<Arc<T>, Error> {
+    let mut c = match cache.get_or_init(Default::default).lock() {
+        Ok(c) => c,
+        Err(e) => e.into_inner(),
+    };
+
+    if let Some(f) = c.get(path) {
+        return Ok(f.clone());
+    }
+
+    debug!("Loading font: {}", path);
+
+    let f = Arc::new(load()?);
+    c.insert(path.to_string(), f.clone());
+
+    Ok(f)
+}
+
+/// Font loaded for text rendering
+pub enum TextFont {
+    /// Built-in bitmap font with integer scale
+    Mono {
+        font: &'static MonoFont<'static>,
+        scale: u32,
+    },
+    /// Bitmap font loaded from a file with integer scale
+    Bitmap { font: Arc<BitmapFont>, scale: u32 },
+    /// Scalable font with size and coverage threshold
+    Scalable {
+        font: Arc<ScalableFont>,
+        scale: PxScale,
+        threshold: u8,
+    },
+}
+
+/// Glyph shaped and positioned relative to the start of a run (in pixels)
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ShapedGlyph {
+    /// Glyph index in the font
+    pub id: u16,
+    /// Horizontal offset from the start of the run
+    pub x: f32,
+    /// Vertical offset from the baseline (positive downwards)
+    pub y: f32,
+}
+
+impl TextFont {
+    /// Load the font described by the provided text options
+    pub fn load(opts: &TextOptions) -> Result<Self, Error> {
+        let scale = opts.scale.max(1);
+
+        match Self::face(opts) {
+            None => Ok(TextFont::Mono {
+                font: opts.font.font(),
+                scale,
+            }),
+            Some(FontFace::Bitmap(f)) => Ok(TextFont::Bitmap {
+                font: cached(&BITMAP_FILES, f, || BitmapFont::load(f))?,
+                scale,
+            }),
+            Some(f) => Self::scalable(f, opts.size.pixels(), opts.threshold),
+        }
+    }
+
+    /// Load a font face with the provided size in pixels, bitmap fonts are
+    /// scaled to the nearest integer multiple of their size
+    pub fn sized(face: &FontFace, px: f32, threshold: u8) -> Result<Self, Error> {
+        match face {
+            FontFace::Bitmap(f) => {
+                let font = cached(&BITMAP_FILES, f, || BitmapFont::load(f))?;
+                let scale = (px / font.height().max(1) as f32).round().max(1.0) as u32;
+
+                Ok(TextFont::Bitmap { font, scale })
+            }
+            _ => Self::scalable(face, px, threshold),
+        }
+    }
+
+    /// Resolve the font face for the provided text options, selecting the bold
+    /// face for bold text where the regular face is scalable
+    fn face(opts: &TextOptions) -> Option<&FontFace> {
+        match (opts.bold, &opts.bold_face, &opts.face) {
+            (_, _, None | Some(FontFace::Bitmap(_))) => opts.face.as_ref(),
+            (true, Some(f), _) => Some(f),
+            (true, None, Some(FontFace::Sans)) => Some(&FontFace::SansBold),
+            (_, _, f) => f.as_ref(),
+        }
+    }
+
+    /// Check whether bold text requires synthetic emboldening (no bold face is available)
+    fn synthetic_bold(opts: &TextOptions) -> bool {
+        match (opts.bold, &opts.face) {
+            (false, _) => false,
+            (true, None | Some(FontFace::Bitmap(_))) => true,
+            (true, Some(FontFace::Sans | FontFace::SansBold)) => false,
+            (true, _) => opts.bold_face.is_none(),
+        }
+    }
+
+    /// Load a scalable font face with the provided size in pixels
+    pub fn scalable(face: &FontFace, px: f32, threshold: u8) -> Result<Self, Error> {
+        static SANS: OnceLock<Arc<ScalableFont>> = OnceLock::new();
+        static SANS_BOLD: OnceLock<Arc<ScalableFont>> = OnceLock::new();
+
+        let font = match face {
+            FontFace::Sans => embedded(&SANS, DEFAULT_FONT),
+            FontFace::SansBold => embedded(&SANS_BOLD, DEFAULT_BOLD_FONT),
+            FontFace::File(f) => cached(&SCALABLE_FILES, f, || {
+                ScalableFont::parse(Cow::Owned(std::fs::read(f)?))
+            })?,
+            FontFace::Bitmap(f) => {
+                return Err(Error::Font(format!("'{}' is not a scalable font", f)));
+            }
+        };
+
+        Ok(TextFont::Scalable {
+            font,
+            scale: PxScale::from(px),
+            threshold,
+        })
+    }
+
+    /// Load the largest font (bitmap or scalable, as selected by the text options)
+    /// where the provided lines fit within `height` pixels (and `width` pixels if set).
+    ///
+    /// If no size fits the smallest allowed size is used and text is clipped.
+    pub fn fit(
+        opts: &TextOptions,
+        lines: &[&str],
+        height: usize,
+        width: Option<usize>,
+    ) -> Result<Self, Error> {
+        let min = opts.min_size.map(|s| s.pixels()).unwrap_or(MIN_FIT_PX);
+        let max = opts.max_size.map(|s| s.pixels()).unwrap_or(height as f32);
+
+        let fits = |f: &TextFont| {
+            f.text_height(lines.len(), opts.line_spacing) <= height
+                && width
+                    .map(|w| lines.iter().all(|l| f.width(l) <= w))
+                    .unwrap_or(true)
+        };
+
+        // Bitmap fonts, pick the largest allowed built-in font (or scale for loaded fonts) that fits
+        let (font, threshold) = match Self::load(opts)? {
+            TextFont::Mono { scale, .. } => {
+                let allowed = FontKind::ALL
+                    .iter()
+                    .map(|k| TextFont::Mono {
+                        font: k.font(),
+                        scale,
+                    })
+                    .collect();
+
+                return Self::fit_bitmap(allowed, min, max, fits);
+            }
+            TextFont::Bitmap { font, .. } => {
+                let allowed = (1..=MAX_BITMAP_SCALE)
+                    .map(|scale| TextFont::Bitmap {
+                        font: font.clone(),
+                        scale,
+                    })
+                    .collect();
+
+                return Self::fit_bitmap(allowed, min, max, fits);
+            }
+            TextFont::Scalable {
+                font, threshold, ..
+            } => (font, threshold),
+        };
+
+        // Scalable fonts, search for the largest size that fits
+        let fits_px = |px: f32| {
+            fits(&TextFont::Scalable {
+                font: font.clone(),
+                scale: PxScale::from(px),
+                threshold,
+            })
+        };
+
+        let px = if fits_px(max) {
+            max
+        } else if !fits_px(min) {
+            min
+        } else {
+            let (mut lo, mut hi) = (min, max);
+            while hi - lo > FIT_PRECISION_PX {
+                let mid = (lo + hi) / 2.0;
+                match fits_px(mid) {
+                    true => lo = mid,
+                    false => hi = mid,
+                }
+            }
+            lo
+        };
+
+        debug!(
+            "Fit {} line(s) to {}px ({:?}) using {}px font",
+            lines.len(),
+            height,
+            width,
+            px
+        );
+
+        Ok(TextFont::Scalable {
+            font,
+            scale: PxScale::from(px),
+            threshold,
+        })
+    }
+
+    /// Pick the largest bitmap font between `min` and `max` pixels that fits,
+    /// or the smallest if none fit
+    fn fit_bitmap(
+        fonts: Vec<TextFont>,
+        min: f32,
+        max: f32,
+        fits: impl Fn(&TextFont) -> bool,
+    ) -> Result<Self, Error> {
+        let mut allowed: Vec<_> = fonts
+            .into_iter()
+            .filter(|f| (f.height() as f32) >= min && (f.height() as f32) <= max)
+            .collect();
+        allowed.sort_by_key(|f| f.height());
+
+        let f = match allowed.iter().rposition(fits) {
+            Some(i) => allowed.into_iter().nth(i),
+            None => allowed.into_iter().next(),
+        };
+
+        f.ok_or_else(|| Error::Font(format!("No bitmap font between {}px and {}px", min, max)))
+    }
+
+    /// Check whether the font contains glyphs for all characters in a grapheme
+    pub fn covers(&self, grapheme: &str) -> bool {
+        match self {
+            TextFont::Mono { font: f, .. } => {
+                // Missing glyphs map to the replacement ('?') glyph
+                let r = f.glyph_mapping.index('?');
+                grapheme
+                    .chars()
+                    .all(|c| c == '?' || f.glyph_mapping.index(c) != r)
+            }
+            TextFont::Bitmap { font, .. } => grapheme.chars().all(|c| font.glyph(c).is_some()),
+            TextFont::Scalable { font, .. } => {
+                grapheme.chars().all(|c| font.font().glyph_id(c).0 != 0)
+            }
+        }
+    }
+
+    /// Compute the width of a line of text in pixels
+    pub fn width(&self, line: &str) -> usize {
+        match self {
+            TextFont::Mono { font: f, scale } => {
+                let n = line.graphemes(true).count();
+                let w = n * f.character_size.width as usize;
+                (w + n.saturating_sub(1) * f.character_spacing as usize) * *scale as usize
+            }
+            TextFont::Bitmap { .. } => line.graphemes(true).map(|g| self.advance(g)).sum(),
+            TextFont::Scalable { .. } => self.shape(line, false, 0.0).1.ceil() as usize,
+        }
+    }
+
+    /// Fetch the advance for a grapheme in pixels (bitmap fonts only)
+    fn advance(&self, grapheme: &str) -> usize {
+        match self {
+            TextFont::Mono { font: f, scale } => {
+                (f.character_size.width + f.character_spacing) as usize * *scale as usize
+            }
+            TextFont::Bitmap { font, scale } => {
+                let a: i32 = grapheme
+                    .chars()
+                    .filter_map(|c| font.glyph(c))
+                    .map(|g| g.advance)
+                    .sum();
+                a.max(0) as usize * *scale as usize
+            }
+            TextFont::Scalable { .. } => 0,
+        }
+    }
+
+    /// Shape text in the provided direction, returning positioned glyphs (in visual
+    /// order) and the advance width in pixels, with `spacing` pixels added between
+    /// characters.
+    ///
+    /// Bitmap fonts are not shaped, returning a glyph per grapheme.
+    pub fn shape(&self, text: &str, rtl: bool, spacing: f32) -> (Vec<ShapedGlyph>, f32) {
+        let (font, scale) = match self {
+            TextFont::Mono { .. } | TextFont::Bitmap { .. } => {
+                // Graphemes in visual order
+                let mut graphemes: Vec<_> = text.graphemes(true).collect();
+                if rtl {
+                    graphemes.reverse();
+                }
+
+                let mut x = 0.0;
+                let mut glyphs = vec![];
+                for g in &graphemes {
+                    glyphs.push(ShapedGlyph { id: 0, x, y: 0.0 });
+                    x += self.advance(g) as f32 + spacing;
+                }
+
+                let n = graphemes.len();
+                let w = self.width(text) as f32 + n.saturating_sub(1) as f32 * spacing;
+                return (glyphs, w);
+            }
+            TextFont::Scalable { font, scale, .. } => (font, scale),
+        };
+
+        let f = font.font().as_scaled(*scale);
+        let mut glyphs = vec![];
+        let mut x = 0.0;
+
+        let face = match font.shaper() {
+            Some(v) => v,
+            None => {
+                // Fallback to unshaped layout with kerning
+                let mut last = None;
+                for c in text.chars() {
+                    let id = f.glyph_id(c);
+                    if let Some(l) = last {
+                        x += f.kern(l, id);
+                    }
+                    glyphs.push(ShapedGlyph {
+                        id: id.0,
+                        x,
+                        y: 0.0,
+                    });
+                    x += f.h_advance(id) + spacing;
+                    last = Some(id);
+                }
+                if !glyphs.is_empty() {
+                    x -= spacing;
+                }
+                return (glyphs, x);
+            }
+        };
+
+        let mut b = UnicodeBuffer::new();
+        b.push_str(text);
+        b.set_direction(match rtl {
+            true => Direction::RightToLeft,
+            false => Direction::LeftToRight,
+        });
+
+        // Positions are in font units, scale to pixels
+        let out = rustybuzz::shape(face, &[], b);
+        let (sx, sy) = (f.h_scale_factor(), f.v_scale_factor());
+
+        let mut spaced = false;
+
+        for (i, p) in out.glyph_infos().iter().zip(out.glyph_positions()) {
+            glyphs.push(ShapedGlyph {
+                id: i.glyph_id as u16,
+                x: x + p.x_offset as f32 * sx,
+                y: -p.y_offset as f32 * sy,
+            });
+            x += p.x_advance as f32 * sx;
+
+            // Letter spacing applies to advancing glyphs only (not combining marks)
+            if p.x_advance != 0 {
+                x += spacing;
+                spaced = true;
+            }
+        }
+
+        if spaced {
+            x -= spacing;
+        }
+
+        (glyphs, x)
+    }
+
+    /// Fetch the height of a line of text in pixels
+    pub fn height(&self) -> usize {
+        match self {
+            TextFont::Mono { font: f, scale } => (f.character_size.height * scale) as usize,
+            TextFont::Bitmap { font, scale } => (font.height() * scale) as usize,
+            TextFont::Scalable { font, scale, .. } => {
+                let f = font.font().as_scaled(*scale);
+                (f.ascent() - f.descent()).ceil() as usize
+            }
+        }
+    }
+
+    /// Fetch the distance from the top of a line to the baseline in pixels
+    pub fn ascent(&self) -> usize {
+        match self {
+            TextFont::Mono { font: f, scale } => (f.baseline * scale) as usize,
+            TextFont::Bitmap { font, scale } => font.ascent.max(0) as usize * *scale as usize,
+            TextFont::Scalable { font, scale, .. } => {
+                font.font().as_scaled(*scale).ascent().ceil() as usize
+            }
+        }
+    }
+
+    /// Fetch the distance between successive lines in pixels, with `spacing`
+    /// overriding the default spacing for the font
+    pub fn line_height(&self, spacing: Option<i32>) -> usize {
+        if let Some(s) = spacing {
+            return (self.height() as i32 + s).max(1) as usize;
+        }
+
+        match self {
+            TextFont::Mono { scale, .. } | TextFont::Bitmap { scale, .. } => {
+                self.height() + MONO_LINE_SPACING * *scale as usize
+            }
+            TextFont::Scalable { font, scale, .. } => {
+                let f = font.font().as_scaled(*scale);
+                (f.ascent() - f.descent() + f.line_gap()).ceil() as usize
+            }
+        }
+    }
+
+    /// Compute the height of a block of text with the provided number of lines
+    pub fn text_height(&self, lines: usize, spacing: Option<i32>) -> usize {
+        self.line_height(spacing) * lines.saturating_sub(1) + self.height()
+    }
+
+    /// Draw a run of text with the top-left corner at the provided point
+    pub fn draw_run<D>(&self, d: &mut D, origin: Point, run: &Run) -> Result<(), Error>
+    where
+        D: DrawTarget<Color = BinaryColor, Error = Error>,
+    {
+        match self {
+            TextFont::Mono { scale, .. } | TextFont::Bitmap { scale, .. } => {
+                // Bitmap fonts are not shaped, reverse right-to-left runs
+                let mut graphemes: Vec<_> = run.text.graphemes(true).collect();
+                if run.rtl {
+                    graphemes.reverse();
+                }
+
+                // Draw per-grapheme so combining characters don't take a cell,
+                // scaling about the grapheme position
+                for (g, sg) in graphemes.iter().zip(run.glyphs.iter()) {
+                    let p = origin + Point::new(sg.x.round() as i32, 0);
+                    let mut d = Scaled {
+                        target: d,
+                        origin: p,
+                        scale: *scale,
+                    };
+
+                    match self {
+                        TextFont::Mono { font: f, .. } => {
+                            let style = MonoTextStyle::new(f, BinaryColor::On);
+                            Text::with_baseline(g, p, style, Baseline::Top).draw(&mut d)?;
+                        }
+                        TextFont::Bitmap { font, .. } => {
+                            let mut pen = p + Point::new(0, font.ascent);
+                            for c in g.chars().filter_map(|c| font.glyph(c)) {
+                                c.draw(&mut d, pen)?;
+                                pen.x += c.advance;
+                            }
+                        }
+                        TextFont::Scalable { .. } => (),
+                    }
+                }
+            }
+            TextFont::Scalable {
+                font,
+                scale,
+                threshold,
+                ..
+            } => {
+                let baseline = origin.y as f32 + font.font().as_scaled(*scale).ascent();
+
+                for sg in &run.glyphs {
+                    let pos = point(origin.x as f32 + sg.x, baseline + sg.y);
+                    let g = GlyphId(sg.id).with_scale_and_position(*scale, pos);
+
+                    let o = match font.font().outline_glyph(g) {
+                        Some(o) => o,
+                        None => continue,
+                    };
+
+                    let b = o.px_bounds();
+                    let mut pixels = vec![];
+
+                    o.draw(|gx, gy, v| {
+                        if (v * 255.0) as u8 >= *threshold {
+                            let p =
+                                Point::new(b.min.x as i32 + gx as i32, b.min.y as i32 + gy as i32);
+                            pixels.push(Pixel(p, BinaryColor::On));
+                        }
+                    });
+
+                    d.draw_iter(pixels)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Run of text rendered with a single font and direction from a [FontChain]
+#[derive(Clone, PartialEq, Debug)]
+pub struct Run {
+    /// Index of the font in the chain
+    pub font: usize,
+    /// Run text (in logical order)
+    pub text: String,
+    /// Right-to-left run
+    pub rtl: bool,
+    /// Shaped glyphs in visual order (one per grapheme for bitmap fonts)
+    pub glyphs: Vec<ShapedGlyph>,
+    /// Run width in pixels
+    pub width: f32,
+}
+
+/// Font fallback chain, the primary font followed by fallback fonts
+/// (sized to match the primary font) used for graphemes the primary font
+/// does not cover.
+pub struct FontChain {
+    fonts: Vec<TextFont>,
+    missing: MissingGlyph,
+    style: TextStyle,
+}
+
+/// Text styles applied when drawing a [FontChain]
+#[derive(Clone, PartialEq, Debug)]
+struct TextStyle {
+    /// Synthetic bold weight in pixels (0 for none)
+    bold: u32,
+    underline: bool,
+    strikethrough: bool,
+    letter_spacing: i32,
+    line_spacing: Option<i32>,
+    /// Text colour, [BinaryColor::Off] for inverse text
+    colour: BinaryColor,
+}
+
+impl FontChain {
+    /// Load the font chain for the provided text options, fitting the primary
+    /// font to the provided lines if enabled (see [TextFont::fit])
+    pub fn load(
+        opts: &TextOptions,
+        lines: &[&str],
+        height: usize,
+        width: Option<usize>,
+    ) -> Result<Self, Error> {
+        let primary = match opts.fit {
+            true => TextFont::fit(opts, lines, height, width)?,
+            false => TextFont::load(opts)?,
+        };
+
+        // Fallbacks are sized to match the primary font, ending with the embedded font
+        let px = primary.height() as f32;
+        let mut fonts = vec![primary];
+
+        for f in opts.fallback.iter().chain(std::iter::once(&FontFace::Sans)) {
+            fonts.push(TextFont::sized(f, px, opts.threshold)?);
+        }
+
+        // Synthetic bold weight scales with the font size
+        let bold = match TextFont::synthetic_bold(opts) {
+            true => (fonts[0].height() as u32 / SYNTHETIC_BOLD_DIV).max(1),
+            false => 0,
+        };
+
+        let style = TextStyle {
+            bold,
+            underline: opts.underline,
+            strikethrough: opts.strikethrough,
+            letter_spacing: opts.letter_spacing,
+            line_spacing: opts.line_spacing,
+            colour: match opts.inverse {
+                true => BinaryColor::Off,
+                false => BinaryColor::On,
+            },
+        };
+
+        Ok(Self {
+            fonts,
+            missing: opts.missing,
+            style,
+        })
+    }
+
+    /// Load the font chain and break text into lines for the provided text options
+    /// (see [TextOptions::wrap]), fitting the primary font to the resulting lines if enabled.
+    ///
+    /// Explicit newlines always break lines.
+    pub fn layout(
+        opts: &TextOptions,
+        text: &str,
+        height: usize,
+        width: Option<usize>,
+    ) -> Result<(Self, Vec<String>), Error> {
+        let paragraphs: Vec<&str> = text.split('\n').collect();
+
+        // Chain loaded while breaking lines, reused where fitting is disabled
+        let (lines, chain) = match opts.wrap {
+            Wrap::None => (paragraphs.iter().map(|p| p.to_string()).collect(), None),
+            Wrap::Lines(n) => {
+                let n = n.max(paragraphs.len());
+                let c = Self::load(opts, &vec![""; n], height, None)?;
+                (c.wrap(&paragraphs, n)?, Some(c))
+            }
+            Wrap::Auto => {
+                // Without fitting, line counts are measured against the configured size
+                let fixed = match opts.fit {
+                    true => None,
+                    false => Some(Self::load(opts, &paragraphs, height, None)?),
+                };
+                let mut best: Option<(usize, Vec<String>)> = None;
+
+                for n in paragraphs.len().. {
+                    // Fitted fonts are sized so n lines fill the label height
+                    let fitted;
+                    let c = match &fixed {
+                        Some(c) => c,
+                        None => {
+                            fitted = Self::load(opts, &vec![""; n], height, None)?;
+                            &fitted
+                        }
+                    };
+
+                    // Stop once lines no longer fit the label
+                    if n > paragraphs.len() && c.text_height(n) > height {
+                        break;
+                    }
+
+                    let lines = c.wrap(&paragraphs, n)?;
+                    let mut w = 0;
+                    for l in &lines {
+                        w = w.max(c.width(l)?);
+                    }
+
+                    // Stop once there are no more words to break
+                    let done = lines.len() < n;
+
+                    // Keep the shortest label, preferring fewer lines
+                    if best.as_ref().map(|b| w < b.0).unwrap_or(true) {
+                        best = Some((w, lines));
+                    }
+
+                    if done {
+                        break;
+                    }
+                }
+
+                (best.map(|b| b.1).unwrap_or_default(), fixed)
+            }
+        };
+
+        debug!("Laid out text in {} line(s): {:?}", lines.len(), lines);
+
+        // Fitted fonts are sized to the resulting lines
+        let chain = match (chain, opts.fit) {
+            (Some(c), false) => c,
+            _ => {
+                let l: Vec<&str> = lines.iter().map(|l| l.as_str()).collect();
+                Self::load(opts, &l, height, width)?
+            }
+        };
+
+        Ok((chain, lines))
+    }
+
+    /// Break paragraphs into (up to) the provided number of lines at whitespace,
+    /// minimising the width of the widest line then balancing line widths.
+    ///
+    /// Each paragraph starts a new line, so at least one line per paragraph is returned.
+    pub fn wrap(&self, paragraphs: &[&str], lines: usize) -> Result<Vec<String>, Error> {
+        // Words with their paragraph index, empty paragraphs keep an empty line
+        let mut words: Vec<(usize, &str)> = vec![];
+        for (i, p) in paragraphs.iter().enumerate() {
+            let w: Vec<_> = p.split_whitespace().collect();
+            if w.is_empty() {
+                words.push((i, ""));
+            }
+            words.extend(w.into_iter().map(|w| (i, w)));
+        }
+
+        let n = words.len();
+        let lines = lines.max(paragraphs.len()).min(n);
+        let join = |i: usize, j: usize| {
+            let w: Vec<_> = words[i..j].iter().map(|w| w.1).collect();
+            w.join(" ")
+        };
+
+        // Measure words and the space between them (including any per-line styling),
+        // to estimate the width of candidate lines
+        let space = self.width("a a")? as i64 - 2 * self.width("a")? as i64;
+        let mut offsets = vec![0i64];
+        for (_, w) in &words {
+            offsets.push(offsets.last().unwrap() + self.width(w)? as i64 + space);
+        }
+
+        // Candidate lines (words i..j) may not span paragraphs
+        let widths = |i: usize, j: usize| match words[i].0 == words[j - 1].0 {
+            true => Some((offsets[j] - offsets[i] - space).max(0) as u64),
+            false => None,
+        };
+
+        // best[k][j] is the cost (widest line, sum of squared widths) and previous
+        // break for the first j words in k lines
+        let mut best = vec![vec![None; n + 1]; lines + 1];
+        best[0][0] = Some((0u64, 0u64, 0usize));
+
+        for k in 1..=lines {
+            for j in 1..=n {
+                for i in k - 1..j {
+                    let ((max, sq, _), w) = match (best[k - 1][i], widths(i, j)) {
+                        (Some(b), Some(w)) => (b, w),
+                        _ => continue,
+                    };
+
+                    let c = (max.max(w), sq + w * w, i);
+                    if best[k][j]
+                        .map(|b: (u64, u64, usize)| (c.0, c.1) < (b.0, b.1))
+                        .unwrap_or(true)
+                    {
+                        best[k][j] = Some(c);
+                    }
+                }
+            }
+        }
+
+        // Walk back through breaks to build lines
+        let mut breaks = vec![n];
+        let mut j = n;
+        for k in (1..=lines).rev() {
+            j = match best[k][j] {
+                Some((_, _, i)) => i,
+                None => return Err(Error::Font("Unable to break text into lines".to_string())),
+            };
+            breaks.push(j);
+        }
+        breaks.reverse();
+
+        Ok(breaks.windows(2).map(|b| join(b[0], b[1])).collect())
+    }
+
+    /// Fetch the primary font
+    pub fn primary(&self) -> &TextFont {
+        &self.fonts[0]
+    }
+
+    /// Split a line into shaped runs in visual order.
+    ///
+    /// Text is normalised (NFC) and split into directional runs using the unicode
+    /// bidi algorithm, then into runs of graphemes sharing the first font covering them.
+    /// Graphemes not covered by any font are replaced or return an error
+    /// depending on [TextOptions::missing].
+    pub fn runs(&self, line: &str) -> Result<Vec<Run>, Error> {
+        let mut runs = vec![];
+
+        // Compose characters where possible (eg. e + U+0301 to é) for bitmap fonts
+        let line: String = line.nfc().collect();
+
+        let bidi = BidiInfo::new(&line, None);
+
+        for para in &bidi.paragraphs {
+            let (levels, visual) = bidi.visual_runs(para, para.range.clone());
+
+            for range in visual {
+                let rtl = levels[range.start].is_rtl();
+
+                let mut r = self.font_runs(&line[range], rtl)?;
+                if rtl {
+                    r.reverse();
+                }
+
+                runs.extend(r);
+            }
+        }
+
+        Ok(runs)
+    }
+
+    /// Split text with a single direction into shaped runs by font (in logical order)
+    fn font_runs(&self, text: &str, rtl: bool) -> Result<Vec<Run>, Error> {
+        let mut runs: Vec<Run> = vec![];
+
+        for g in text.graphemes(true) {
+            let (font, g) = match self.fonts.iter().position(|f| f.covers(g)) {
+                Some(i) => (i, g),
+                None => match self.missing {
+                    MissingGlyph::Error => {
+                        let codes: Vec<_> =
+                            g.chars().map(|c| format!("U+{:04X}", c as u32)).collect();
+                        return Err(Error::Font(format!(
+                            "No font covers '{}' ({})",
+                            g,
+                            codes.join(" ")
+                        )));
+                    }
+                    MissingGlyph::Replace => {
+                        match self.fonts.iter().position(|f| f.covers(REPLACEMENT)) {
+                            Some(i) => (i, REPLACEMENT),
+                            None => (0, "?"),
+                        }
+                    }
+                },
+            };
+
+            match runs.last_mut() {
+                Some(r) if r.font == font => r.text.push_str(g),
+                _ => runs.push(Run {
+                    font,
+                    text: g.to_string(),
+                    rtl,
+                    glyphs: vec![],
+                    width: 0.0,
+                }),
+            }
+        }
+
+        // Shape each run with its font
+        for r in runs.iter_mut() {
+            let (glyphs, width) =
+                self.fonts[r.font].shape(&r.text, rtl, self.style.letter_spacing as f32);
+            r.glyphs = glyphs;
+            r.width = width;
+        }
+
+        Ok(runs)
+    }
+
+    /// Compute the width of a line of text in pixels
+    pub fn width(&self, line: &str) -> Result<usize, Error> {
+        let runs = self.runs(line)?;
+        Ok(self.runs_width(&runs).ceil() as usize)
+    }
+
+    /// Compute the width of shaped runs, including letter spacing between runs
+    /// and synthetic bold
+    fn runs_width(&self, runs: &[Run]) -> f32 {
+        let w: f32 = runs.iter().map(|r| r.width).sum();
+        let spacing = runs.len().saturating_sub(1) as f32 * self.style.letter_spacing as f32;
+
+        (w + spacing + self.style.bold as f32).max(0.0)
+    }
+
+    /// Compute the height of a block of text with the provided number of lines
+    pub fn text_height(&self, lines: usize) -> usize {
+        self.primary().text_height(lines, self.style.line_spacing)
+    }
+
+    /// Fetch the distance between successive lines in pixels
+    pub fn line_height(&self) -> usize {
+        self.primary().line_height(self.style.line_spacing)
+    }
+
+    /// Draw a line of text with the top-left corner at the provided point,
+    /// aligning fallback fonts to the primary font baseline and applying text styles
+    pub fn draw<D>(&self, d: &mut D, origin: Point, line: &str) -> Result<(), Error>
+    where
+        D: DrawTarget<Color = BinaryColor, Error = Error>,
+    {
+        let primary = self.primary();
+        let baseline = origin.y + primary.ascent() as i32;
+        let runs = self.runs(line)?;
+
+        let mut styled = Styled {
+            target: d,
+            bold: self.style.bold,
+            colour: self.style.colour,
+        };
+
+        let mut x = origin.x as f32;
+        for r in &runs {
+            let f = &self.fonts[r.font];
+
+            let p = Point::new(x.round() as i32, baseline - f.ascent() as i32);
+            f.draw_run(&mut styled, p, r)?;
+
+            x += r.width + self.style.letter_spacing as f32;
+        }
+
+        // Decorations span the line with thickness scaled to the font
+        let width = self.runs_width(&runs).ceil() as u32;
+        let thickness = (primary.height() / DECORATION_DIV).max(1) as u32;
+        let descent = (primary.height() - primary.ascent()) as i32;
+        let style = PrimitiveStyle::with_fill(self.style.colour);
+
+        if self.style.underline {
+            let y = baseline + (descent / 3).max(1);
+            Rectangle::new(Point::new(origin.x, y), Size::new(width, thickness))
+                .into_styled(style)
+                .draw(d)?;
+        }
+
+        if self.style.strikethrough {
+            let y = baseline - (primary.ascent() as i32 * 3 / 10) - thickness as i32 / 2;
+            Rectangle::new(Point::new(origin.x, y), Size::new(width, thickness))
+                .into_styled(style)
+                .draw(d)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Draw target adapter scaling drawn pixels by an integer factor about an origin
+struct Scaled<'a, D> {
+    target: &'a mut D,
+    origin: Point,
+    scale: u32,
+}
+
+impl<D> Dimensions for Scaled<'_, D>
+where
+    D: DrawTarget<Color = BinaryColor, Error = Error>,
+{
+    fn bounding_box(&self) -> Rectangle {
+        self.target.bounding_box()
+    }
+}
+
+impl<D> DrawTarget for Scaled<'_, D>
+where
+    D: DrawTarget<Color = BinaryColor, Error = Error>,
+{
+    type Color = BinaryColor;
+    type Error = Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let (origin, s) = (self.origin, self.scale as i32);
+
+        let pixels = pixels.into_iter().flat_map(move |Pixel(p, c)| {
+            let base = origin + (p - origin) * s;
+            (0..s * s).map(move |i| Pixel(base + Point::new(i % s, i / s), c))
+        });
+
+        self.target.draw_iter(pixels)
+    }
+}
+
+/// Draw target adapter applying synthetic bold and the text colour to drawn pixels
+struct Styled<'a, D> {
+    target: &'a mut D,
+    bold: u32,
+    colour: BinaryColor,
+}
+
+impl<D> Dimensions for Styled<'_, D>
+where
+    D: DrawTarget<Color = BinaryColor, Error = Error>,
+{
+    fn bounding_box(&self) -> Rectangle {
+        self.target.bounding_box()
+    }
+}
+
+impl<D> DrawTarget for Styled<'_, D>
+where
+    D: DrawTarget<Color = BinaryColor, Error = Error>,
+{
+    type Color = BinaryColor;
+    type Error = Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let (bold, colour) = (self.bold as i32, self.colour);
+
+        // Emboldening smears set pixels to the right
+        let pixels =
+            pixels
+                .into_iter()
+                .filter(|Pixel(_, c)| c.is_on())
+                .flat_map(move |Pixel(p, _)| {
+                    (0..=bold).map(move |dx| Pixel(p + Point::new(dx, 0), colour))
+                });
+
+        self.target.draw_iter(pixels)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render::{Display, FontSize};
+
+    #[test]
+    fn test_text_metrics() {
+        let mono = TextFont::load(&TextOptions::default()).unwrap();
+        assert_eq!(mono.width("abc"), 36);
+        assert_eq!(mono.height(), 16);
+
+        let sans = TextFont::load(&TextOptions {
+            face: Some(FontFace::Sans),
+            size: "9pt".parse().unwrap(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Proportional widths from font metrics
+        assert!(sans.width("iii") < sans.width("WWW"));
+        assert_eq!(sans.height(), 23);
+
+        // Fit to label height
+        let opts = TextOptions {
+            fit: true,
+            ..Default::default()
+        };
+        assert_eq!(TextFont::fit(&opts, &[""], 70, None).unwrap().height(), 32);
+        assert_eq!(
+            TextFont::fit(&opts, &[""; 2], 50, None).unwrap().height(),
+            16
+        );
+
+        // Fit line widths for rotated text
+        let f = TextFont::fit(&opts, &["abcdefgh"], 128, Some(70)).unwrap();
+        assert_eq!(f.height(), 16);
+
+        let opts = TextOptions {
+            face: Some(FontFace::Sans),
+            max_size: Some(FontSize::Px(40.0)),
+            ..opts
+        };
+        let f = TextFont::fit(&opts, &[""; 2], 70, None).unwrap();
+        assert!(f.text_height(2, None) <= 70 && f.text_height(2, None) > 60);
+        let f = TextFont::fit(&opts, &[""], 128, None).unwrap();
+        assert!(f.text_height(1, None) <= 48);
+
+        assert_eq!("3mm".parse(), Ok(FontSize::Mm(3.0)));
+        assert_eq!("20".parse(), Ok(FontSize::Px(20.0)));
+        assert!("big".parse::<FontSize>().is_err());
+    }
+
+    #[test]
+    fn test_font_fallback() {
+        let c = FontChain::load(&TextOptions::default(), &[""], 64, None).unwrap();
+
+        // Latin-1 is covered by the bitmap font, Greek falls back to the embedded font
+        let runs = c.runs("Büro 4.7µF 10Ω").unwrap();
+        assert_eq!(runs[0].text, "Büro 4.7µF 10");
+        assert_eq!(runs[1].font, 1);
+        assert_eq!(runs[1].text, "Ω");
+
+        // Combining characters are measured as a single grapheme
+        assert_eq!(c.primary().width("e\u{301}x"), c.primary().width("ex"));
+
+        // Decomposed characters are composed where possible
+        assert_eq!(c.runs("e\u{301}").unwrap()[0].font, 0);
+
+        // Uncovered graphemes are replaced or rejected
+        let runs = c.runs("\u{E000}").unwrap();
+        assert_eq!(runs[0].text, REPLACEMENT);
+
+        let c = FontChain::load(
+            &TextOptions {
+                missing: MissingGlyph::Error,
+                ..Default::default()
+            },
+            &[""],
+            64,
+            None,
+        )
+        .unwrap();
+        assert!(c.runs("\u{E000}").is_err());
+    }
+
+    #[test]
+    fn test_text_style() {
+        let c = FontChain::load(&TextOptions::default(), &[""], 64, None).unwrap();
+        assert_eq!(c.width("abc").unwrap(), 36);
+        assert_eq!(c.line_height(), 20);
+
+        // Synthetic bold, letter and line spacing for bitmap fonts
+        let opts = TextOptions {
+            bold: true,
+            letter_spacing: 2,
+            line_spacing: Some(0),
+            ..Default::default()
+        };
+        let c = FontChain::load(&opts, &[""], 64, None).unwrap();
+        assert_eq!(c.width("abc").unwrap(), 36 + 2 * 2 + 1);
+        assert_eq!(c.line_height(), 16);
+
+        // Bold uses the embedded bold font where available
+        let opts = TextOptions {
+            face: Some(FontFace::Sans),
+            bold: true,
+            ..Default::default()
+        };
+        assert!(!TextFont::synthetic_bold(&opts));
+        assert_eq!(TextFont::face(&opts), Some(&FontFace::SansBold));
+
+        let opts = TextOptions {
+            face: Some(FontFace::File("font.ttf".to_string())),
+            ..opts
+        };
+        assert!(TextFont::synthetic_bold(&opts));
+
+        // Bold faces are not used in place of bitmap fonts
+        let opts = TextOptions {
+            face: None,
+            bold_face: Some(FontFace::SansBold),
+            ..opts
+        };
+        assert_eq!(TextFont::face(&opts), None);
+        assert!(TextFont::synthetic_bold(&opts));
+
+        let opts = TextOptions {
+            face: Some(FontFace::Bitmap("font.bdf".to_string())),
+            ..opts
+        };
+        assert_eq!(TextFont::face(&opts), opts.face.as_ref());
+        assert!(TextFont::synthetic_bold(&opts));
+    }
+
+    #[test]
+    fn test_text_wrap() {
+        let c = FontChain::load(&TextOptions::default(), &[""], 64, None).unwrap();
+
+        // Lines are balanced
+        let lines = c.wrap(&["aaaa bb cc dddd"], 2).unwrap();
+        assert_eq!(lines, vec!["aaaa bb", "cc dddd"]);
+
+        // Paragraphs always start a new line
+        let lines = c.wrap(&["a b", "", "c"], 3).unwrap();
+        assert_eq!(lines, vec!["a b", "", "c"]);
+        let lines = c.wrap(&["a b", "c"], 4).unwrap();
+        assert_eq!(lines, vec!["a", "b", "c"]);
+
+        // Automatic wrapping uses as many lines as fit
+        let opts = TextOptions {
+            wrap: Wrap::Auto,
+            ..Default::default()
+        };
+        let (_, lines) = FontChain::layout(&opts, "aaaa bb cc dddd ee ff", 64, None).unwrap();
+        assert_eq!(lines, vec!["aaaa bb", "cc dddd", "ee ff"]);
+
+        // Fitted text is broken where a smaller font gives a shorter label
+        let text = "aaaa bb cc dddd ee ff";
+        let width = |(c, lines): (FontChain, Vec<String>)| {
+            let w = lines.iter().map(|l| c.width(l).unwrap()).max().unwrap();
+            (lines.len(), w)
+        };
+
+        let opts = TextOptions { fit: true, ..opts };
+        let (n, w) = width(FontChain::layout(&opts, text, 64, None).unwrap());
+
+        let single = TextOptions {
+            wrap: Wrap::None,
+            ..opts
+        };
+        let (_, single) = width(FontChain::layout(&single, text, 64, None).unwrap());
+
+        assert!(n > 1);
+        assert!(w < single);
+
+        assert_eq!("auto".parse(), Ok(Wrap::Auto));
+        assert_eq!("3".parse(), Ok(Wrap::Lines(3)));
+        assert!("0".parse::<Wrap>().is_err());
+    }
+
+    #[test]
+    fn test_font_cache() {
+        let d = tempdir::TempDir::new("ptouch-font").unwrap();
+        let path = d.path().join("font.ttf");
+        std::fs::write(&path, DEFAULT_FONT).unwrap();
+
+        let face = FontFace::File(path.to_string_lossy().to_string());
+        let font = |px| match TextFont::sized(&face, px, 128).unwrap() {
+            TextFont::Scalable { font, .. } => font,
+            _ => unreachable!(),
+        };
+
+        // File fonts are loaded once and shared across sizes
+        let (a, b) = (font(16.0), font(32.0));
+        assert!(Arc::ptr_eq(&a, &b));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(Arc::ptr_eq(&a, &font(24.0)));
+    }
+
+    #[test]
+    fn test_text_scale() {
+        let f = TextFont::load(&TextOptions::default()).unwrap();
+        let opts = TextOptions {
+            scale: 2,
+            ..Default::default()
+        };
+        let s = TextFont::load(&opts).unwrap();
+        assert_eq!(s.width("abc"), f.width("abc") * 2);
+        assert_eq!(s.height(), f.height() * 2);
+
+        // Scaled glyphs are drawn as blocks of pixels
+        let c = FontChain::load(&opts, &[""], 64, None).unwrap();
+        let mut d = Display::new(s.height(), s.width("-"));
+        for r in c.runs("-").unwrap() {
+            s.draw_run(&mut d, Point::zero(), &r).unwrap();
+        }
+        let on: Vec<_> = (0..s.width("-"))
+            .flat_map(|x| (0..s.height()).map(move |y| (x, y)))
+            .filter(|(x, y)| d.get(*x, *y).unwrap())
+            .collect();
+        assert!(!on.is_empty() && on.len() % 4 == 0);
+
+        // Bitmap font faces are detected by extension
+        assert_eq!(
+            FontFace::from("fonts/9x18.BDF".to_string()),
+            FontFace::Bitmap("fonts/9x18.BDF".to_string())
+        );
+        let opts = TextOptions {
+            face: Some(FontFace::Bitmap("missing.pcf".to_string())),
+            ..Default::default()
+        };
+        assert!(TextFont::load(&opts).is_err());
+    }
+
+    #[test]
+    fn test_text_shaping() {
+        let opts = TextOptions {
+            face: Some(FontFace::Sans),
+            ..Default::default()
+        };
+        let c = FontChain::load(&opts, &[""], 64, None).unwrap();
+        let f = match c.primary() {
+            TextFont::Scalable { font, .. } => font.clone(),
+            _ => unreachable!(),
+        };
+
+        // Right-to-left text is laid out in visual order
+        let runs = c.runs("שלום").unwrap();
+        assert!(runs[0].rtl);
+        assert_eq!(runs[0].glyphs[0].id, f.font().glyph_id('ם').0);
+
+        // Arabic letters are shaped into contextual forms and ligatures (lam-alef)
+        let runs = c.runs("سلام").unwrap();
+        assert_eq!(runs[0].glyphs.len(), 3);
+        assert_ne!(runs[0].glyphs[2].id, f.font().glyph_id('س').0);
+
+        // Mixed direction text is split into directional runs
+        let runs = c.runs("abc שלום").unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!((runs[0].text.as_str(), runs[0].rtl), ("abc ", false));
+        assert_eq!((runs[1].text.as_str(), runs[1].rtl), ("שלום", true));
+    }
+}
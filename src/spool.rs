@@ -0,0 +1,477 @@
+//! Offline job spooling, persisting jobs to disk until a printer is available
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::device::PrintInfo;
+use crate::media::MediaRegistry;
+use crate::render::{Op, Orientation, Render, RenderConfig};
+use crate::{Error, PTouch};
+
+/// Spooled job file extension
+const JOB_EXT: &str = "toml";
+
+/// Dead-letter directory (within the spool) for jobs that could not be printed
+const FAILED_DIR: &str = "failed";
+
+/// Default delay before retrying a failed job, doubled for each failed attempt
+pub const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Maximum delay before retrying a failed job
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(300);
+
+/// Counter to keep job names unique within a process
+static JOB_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Spooled job content
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SpoolContent {
+    /// Render operations, rendered against the loaded media at print time
+    Ops {
+        /// Label orientation used when rendering
+        #[serde(default)]
+        orientation: Orientation,
+        ops: Vec<Op>,
+        /// Print information (copies and cut mode), media and raster fields
+        /// are set from the loaded media at print time
+        #[serde(default)]
+        info: PrintInfo,
+    },
+    /// Pre-rendered raster data with print information
+    Raster {
+        data: Vec<[u8; 16]>,
+        info: PrintInfo,
+    },
+}
+
+/// Spooled print job
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SpoolJob {
+    /// Do not feed and cut after printing (see [PrintInfo::chain])
+    #[serde(default)]
+    pub chain: bool,
+
+    /// Number of failed print attempts
+    #[serde(default)]
+    pub attempts: u32,
+
+    /// Most recent print error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// Job content
+    pub content: SpoolContent,
+}
+
+impl SpoolJob {
+    /// Create a job from render operations, label orientation and (partial) print information
+    pub fn ops(ops: Vec<Op>, orientation: Orientation, info: PrintInfo) -> Self {
+        Self {
+            chain: info.chain,
+            attempts: 0,
+            error: None,
+            content: SpoolContent::Ops {
+                ops,
+                orientation,
+                info,
+            },
+        }
+    }
+
+    /// Create a job from raster data and print information
+    pub fn raster(data: Vec<[u8; 16]>, info: PrintInfo) -> Self {
+        Self {
+            chain: info.chain,
+            attempts: 0,
+            error: None,
+            content: SpoolContent::Raster { data, info },
+        }
+    }
+
+    /// Print the job, resolving loaded media using the provided registry.
+    ///
+    /// Device status and loaded media are checked before printing (see [PTouch::print_raw]).
+    pub fn print(&self, ptouch: &mut PTouch, registry: &MediaRegistry) -> Result<(), Error> {
+        match &self.content {
+            SpoolContent::Ops {
+                ops,
+                orientation,
+                info,
+            } => {
+                // Fetch media from the printer to setup rendering
+                let status = ptouch.status()?;
+                let media = registry.lookup(status.media_kind, status.media_width)?;
+
+                let mut r = Render::new(RenderConfig {
+                    y: media.pins,
+                    orientation: *orientation,
+                    ..Default::default()
+                });
+                r.render(ops)?;
+
+                let data = r
+                    .raster(media.area())
+                    .map_err(|e| Error::Spool(e.to_string()))?;
+
+                let info = PrintInfo {
+                    width: Some(status.media_width),
+                    length: Some(media.length.unwrap_or(0)),
+                    raster_no: data.len() as u32,
+                    chain: self.chain,
+                    ..info.clone()
+                };
+
+                ptouch.print_raw(data, &info)
+            }
+            SpoolContent::Raster { data, info } => {
+                let info = PrintInfo {
+                    chain: self.chain,
+                    ..info.clone()
+                };
+
+                ptouch.print_raw(data.clone(), &info)
+            }
+        }
+    }
+}
+
+/// Result of draining the spool
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DrainResult {
+    /// Jobs printed successfully
+    pub printed: usize,
+    /// Jobs that failed and will be retried
+    pub retried: usize,
+    /// Failed jobs skipped as their retry delay has not elapsed
+    pub deferred: usize,
+    /// Jobs moved to the dead-letter directory
+    pub failed: usize,
+}
+
+/// On-disk job spool.
+///
+/// Jobs are stored as individual TOML files, printed (and removed) in submission
+/// order by [Spool::drain]. Jobs that fail more than the allowed number of
+/// attempts are moved to the `failed` directory with the error recorded, failed
+/// jobs are retried with an increasing delay (see [Spool::set_retry_delay]).
+pub struct Spool {
+    dir: PathBuf,
+    media: MediaRegistry,
+    retry_delay: Duration,
+}
+
+impl Spool {
+    /// Open (creating if required) a spool directory
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+
+        std::fs::create_dir_all(dir.join(FAILED_DIR))?;
+
+        Ok(Self {
+            dir,
+            media: MediaRegistry::default(),
+            retry_delay: RETRY_DELAY,
+        })
+    }
+
+    /// Set the delay before retrying a failed job, doubled for each further attempt
+    pub fn set_retry_delay(&mut self, delay: Duration) {
+        self.retry_delay = delay;
+    }
+
+    /// Compute the delay before retrying a job with the provided number of failed attempts
+    fn retry_delay(&self, attempts: u32) -> Duration {
+        let shift = attempts.saturating_sub(1).min(16);
+        (self.retry_delay * (1 << shift)).min(RETRY_DELAY_MAX.max(self.retry_delay))
+    }
+
+    /// Check whether the retry delay for a failed job has elapsed, using the time
+    /// the job was last updated
+    fn retry_due(&self, path: &Path, job: &SpoolJob) -> bool {
+        if job.attempts == 0 {
+            return true;
+        }
+
+        let updated = match std::fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(t) => t,
+            Err(_) => return true,
+        };
+
+        match updated.elapsed() {
+            Ok(e) => e >= self.retry_delay(job.attempts),
+            Err(_) => false,
+        }
+    }
+
+    /// Set the media registry used to render spooled operations
+    pub fn set_media(&mut self, media: MediaRegistry) {
+        self.media = media;
+    }
+
+    /// Add a job to the spool, returning the job file path
+    pub fn submit(&self, job: &SpoolJob) -> Result<PathBuf, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let name = format!(
+            "{:016}-{}-{}",
+            now.as_millis(),
+            std::process::id(),
+            JOB_COUNT.fetch_add(1, Ordering::SeqCst)
+        );
+
+        let path = self.dir.join(&name).with_extension(JOB_EXT);
+
+        // Write to a temporary file then rename, so partial jobs are never picked up
+        let tmp = self.dir.join(&name).with_extension("tmp");
+        std::fs::write(&tmp, encode(job)?)?;
+        std::fs::rename(&tmp, &path)?;
+
+        debug!("Spooled job: {}", path.display());
+
+        Ok(path)
+    }
+
+    /// List pending job files in submission order
+    pub fn pending(&self) -> Result<Vec<PathBuf>, Error> {
+        Self::jobs(&self.dir)
+    }
+
+    /// List failed (dead-letter) job files
+    pub fn failed(&self) -> Result<Vec<PathBuf>, Error> {
+        Self::jobs(&self.dir.join(FAILED_DIR))
+    }
+
+    /// List job files in a directory in submission order
+    fn jobs(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut jobs = vec![];
+
+        for e in std::fs::read_dir(dir)? {
+            let p = e?.path();
+            if p.is_file() && p.extension().map(|e| e == JOB_EXT).unwrap_or(false) {
+                jobs.push(p);
+            }
+        }
+
+        jobs.sort();
+
+        Ok(jobs)
+    }
+
+    /// Print pending jobs, removing them from the spool on success.
+    ///
+    /// Failed jobs are retried (once their retry delay has elapsed) up to `max_attempts`
+    /// times before being moved to the dead-letter directory. Draining stops (leaving
+    /// remaining jobs spooled) if the printer is disconnected.
+    pub fn drain(&self, ptouch: &mut PTouch, max_attempts: u32) -> Result<DrainResult, Error> {
+        // Loaded media is checked against the spool registry before printing
+        ptouch.set_media_registry(self.media.clone());
+
+        self.drain_with(|job| job.print(ptouch, &self.media), max_attempts)
+    }
+
+    /// Drain pending jobs (as with [Spool::drain]) using the provided print function
+    pub fn drain_with(
+        &self,
+        mut print: impl FnMut(&SpoolJob) -> Result<(), Error>,
+        max_attempts: u32,
+    ) -> Result<DrainResult, Error> {
+        let mut result = DrainResult::default();
+
+        for path in self.pending()? {
+            let mut job = match std::fs::read_to_string(&path)
+                .map_err(Error::from)
+                .and_then(|s| decode(&s))
+            {
+                Ok(j) => j,
+                Err(e) => {
+                    warn!("Invalid spool job {}: {}", path.display(), e);
+                    self.dead_letter(&path, None, &e)?;
+                    result.failed += 1;
+                    continue;
+                }
+            };
+
+            if !self.retry_due(&path, &job) {
+                debug!("Deferring spooled job: {}", path.display());
+                result.deferred += 1;
+                continue;
+            }
+
+            info!("Printing spooled job: {}", path.display());
+
+            let e = match print(&job) {
+                Ok(_) => {
+                    std::fs::remove_file(&path)?;
+                    result.printed += 1;
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            // Printer went away, leave this and remaining jobs for next time
+            if let Error::Usb(rusb::Error::NoDevice) = e {
+                warn!("Printer disconnected, stopping spool");
+                return Err(e);
+            }
+
+            warn!("Spooled job {} failed: {}", path.display(), e);
+
+            job.attempts += 1;
+
+            if job.attempts >= max_attempts {
+                self.dead_letter(&path, Some(&mut job), &e)?;
+                result.failed += 1;
+            } else {
+                job.error = Some(e.to_string());
+                std::fs::write(&path, encode(&job)?)?;
+                result.retried += 1;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Move a job to the dead-letter directory, recording the error
+    fn dead_letter(&self, path: &Path, job: Option<&mut SpoolJob>, e: &Error) -> Result<(), Error> {
+        let name = path.file_name().unwrap_or_default();
+        let failed = self.dir.join(FAILED_DIR).join(name);
+
+        match job {
+            Some(job) => {
+                job.error = Some(e.to_string());
+                std::fs::write(&failed, encode(job)?)?;
+                std::fs::remove_file(path)?;
+            }
+            None => {
+                // Unparsable jobs are moved as-is, with the error alongside
+                std::fs::rename(path, &failed)?;
+                std::fs::write(failed.with_extension("error"), e.to_string())?;
+            }
+        }
+
+        warn!("Moved job to {}", failed.display());
+
+        Ok(())
+    }
+}
+
+fn encode(job: &SpoolJob) -> Result<String, Error> {
+    toml::to_string(job).map_err(|e| Error::Spool(e.to_string()))
+}
+
+fn decode(s: &str) -> Result<SpoolJob, Error> {
+    toml::from_str(s).map_err(|e| Error::Spool(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::CutMode;
+
+    #[test]
+    fn test_spool_submit() {
+        let d = tempdir::TempDir::new("ptouch-spool").unwrap();
+        let s = Spool::new(d.path()).unwrap();
+
+        let info = PrintInfo {
+            copies: 3,
+            cut: CutMode::HalfCut,
+            ..Default::default()
+        };
+        let a = SpoolJob::ops(
+            vec![Op::pad(16), Op::text("hello")],
+            Orientation::Portrait,
+            info,
+        );
+        let b = SpoolJob::raster(vec![[0xaa; 16]; 2], PrintInfo::default());
+
+        let pa = s.submit(&a).unwrap();
+        let pb = s.submit(&b).unwrap();
+
+        assert_eq!(s.pending().unwrap(), vec![pa.clone(), pb.clone()]);
+
+        let ra = decode(&std::fs::read_to_string(&pa).unwrap()).unwrap();
+        assert_eq!(ra, a);
+
+        let rb = decode(&std::fs::read_to_string(&pb).unwrap()).unwrap();
+        assert_eq!(rb, b);
+    }
+
+    #[test]
+    fn test_spool_drain() {
+        let d = tempdir::TempDir::new("ptouch-spool").unwrap();
+        let mut s = Spool::new(d.path()).unwrap();
+
+        let info = PrintInfo::default();
+        let ok = s
+            .submit(&SpoolJob::ops(
+                vec![Op::text("ok")],
+                Orientation::default(),
+                info.clone(),
+            ))
+            .unwrap();
+        let bad = s
+            .submit(&SpoolJob::ops(
+                vec![Op::text("bad")],
+                Orientation::default(),
+                info,
+            ))
+            .unwrap();
+
+        let print = |j: &SpoolJob| match &j.content {
+            SpoolContent::Ops { ops, .. } if ops[0] == Op::text("ok") => Ok(()),
+            _ => Err(Error::Render),
+        };
+
+        // Successful jobs are removed, failed jobs are retried
+        let r = s.drain_with(print, 2).unwrap();
+        assert_eq!((r.printed, r.retried, r.failed), (1, 1, 0));
+        assert!(!ok.exists());
+
+        let j = decode(&std::fs::read_to_string(&bad).unwrap()).unwrap();
+        assert_eq!(j.attempts, 1);
+        assert!(j.error.is_some());
+
+        // Failed jobs are not retried until the retry delay has elapsed
+        let r = s.drain_with(print, 2).unwrap();
+        assert_eq!((r.deferred, r.failed), (1, 0));
+
+        // Jobs that keep failing are moved to the dead-letter directory
+        s.set_retry_delay(Duration::ZERO);
+        let r = s.drain_with(print, 2).unwrap();
+        assert_eq!(r.failed, 1);
+        assert!(s.pending().unwrap().is_empty());
+
+        let failed = s.failed().unwrap();
+        assert_eq!(failed.len(), 1);
+        let j = decode(&std::fs::read_to_string(&failed[0]).unwrap()).unwrap();
+        assert_eq!(j.attempts, 2);
+        assert_eq!(j.error, Some(Error::Render.to_string()));
+
+        // Unparsable jobs are moved with the error alongside, which is not listed as a job
+        let invalid = d.path().join("invalid").with_extension(JOB_EXT);
+        std::fs::write(&invalid, "kind = ").unwrap();
+        let r = s.drain_with(print, 2).unwrap();
+        assert_eq!(r.failed, 1);
+
+        let failed = s.failed().unwrap();
+        assert_eq!(failed.len(), 2);
+        assert!(d.path().join(FAILED_DIR).join("invalid.error").exists());
+
+        // Retry delays back off with attempts
+        s.set_retry_delay(RETRY_DELAY);
+        assert_eq!(s.retry_delay(1), RETRY_DELAY);
+        assert_eq!(s.retry_delay(3), RETRY_DELAY * 4);
+        assert_eq!(s.retry_delay(100), RETRY_DELAY_MAX);
+    }
+}
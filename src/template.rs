@@ -0,0 +1,226 @@
+//! P-touch Template mode support, printing templates stored on the device
+// Rust PTouch Driver / Utility
+//
+// https://github.com/ryankurte/rust-ptouch
+// Copyright 2021 Ryan Kurte
+//
+// Templates are created in P-touch Editor and transferred to the printer
+// with a key (1-99), see the Brother P-touch Template Command Reference.
+// Note that template mode is only supported on the PT-E550W and PT-P750W.
+
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use log::debug;
+
+use crate::commands::Commands;
+use crate::device::Mode;
+use crate::progress::CancelToken;
+use crate::{Error, PTouch};
+
+/// Valid template keys
+const KEYS: RangeInclusive<u8> = 1..=99;
+/// Valid object indices and copy counts (three digit parameters)
+const COUNTS: RangeInclusive<u16> = 1..=999;
+
+/// Template object selector
+#[derive(Clone, PartialEq, Debug)]
+pub enum Object {
+    /// Select an object by name (as set in P-touch Editor)
+    Name(String),
+    /// Select an object by number (in template order, starting from 1)
+    Index(u16),
+}
+
+impl std::str::FromStr for Object {
+    type Err = std::convert::Infallible;
+
+    /// Parse an object selector, numeric values select by index
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<u16>() {
+            Ok(i) => Ok(Object::Index(i)),
+            Err(_) => Ok(Object::Name(s.to_string())),
+        }
+    }
+}
+
+/// Stored template print job
+#[derive(Clone, PartialEq, Debug)]
+pub struct TemplateJob {
+    /// Template key (1-99)
+    pub key: u8,
+    /// Object data (text or barcode content) to insert
+    pub fields: Vec<(Object, String)>,
+    /// Number of copies to print (1-999)
+    pub copies: u16,
+}
+
+impl TemplateJob {
+    /// Create a new job for the template with the provided key
+    pub fn new(key: u8) -> Self {
+        Self {
+            key,
+            fields: vec![],
+            copies: 1,
+        }
+    }
+
+    /// Fill an object with the provided value
+    pub fn field(&mut self, object: Object, value: &str) -> &mut Self {
+        self.fields.push((object, value.to_string()));
+        self
+    }
+
+    /// Set the number of copies to print
+    pub fn copies(&mut self, copies: u16) -> &mut Self {
+        self.copies = copies;
+        self
+    }
+
+    /// Check the job parameters are within the ranges supported by the device
+    pub fn validate(&self) -> Result<(), Error> {
+        if !KEYS.contains(&self.key) {
+            return Err(Error::InvalidTemplate(format!(
+                "key {} outside {:?}",
+                self.key, KEYS
+            )));
+        }
+
+        if !COUNTS.contains(&self.copies) {
+            return Err(Error::InvalidTemplate(format!(
+                "copies {} outside {:?}",
+                self.copies, COUNTS
+            )));
+        }
+
+        for (object, value) in &self.fields {
+            match object {
+                Object::Index(i) if !COUNTS.contains(i) => {
+                    return Err(Error::InvalidTemplate(format!(
+                        "object index {} outside {:?}",
+                        i, COUNTS
+                    )));
+                }
+                Object::Name(n) if n.is_empty() || n.contains('\0') => {
+                    return Err(Error::InvalidTemplate(format!(
+                        "invalid object name '{}'",
+                        n
+                    )));
+                }
+                _ => (),
+            }
+
+            // Data length is a 16-bit prefix
+            if value.len() > u16::MAX as usize {
+                return Err(Error::InvalidTemplate(format!(
+                    "value for {:?} too long ({} bytes)",
+                    object,
+                    value.len()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encode the job as a template mode command sequence
+    pub fn data(&self) -> Result<Vec<u8>, Error> {
+        self.validate()?;
+
+        let mut buff = vec![];
+
+        // Switch to template mode
+        buff.extend_from_slice(&[0x1b, 0x69, 0x61, Mode::PTouchTemplate as u8]);
+        // Initialise template mode
+        buff.extend_from_slice(b"^II");
+
+        // Select template
+        buff.extend_from_slice(format!("^TS{:03}", self.key).as_bytes());
+
+        for (object, value) in &self.fields {
+            // Select object
+            match object {
+                Object::Name(n) => {
+                    buff.extend_from_slice(b"^ON");
+                    buff.extend_from_slice(n.as_bytes());
+                    buff.push(0x00);
+                }
+                Object::Index(i) => {
+                    buff.extend_from_slice(format!("^OS{:03}", i).as_bytes());
+                }
+            }
+
+            // Insert data
+            let len = value.len() as u16;
+            buff.extend_from_slice(b"^DI");
+            buff.extend_from_slice(&len.to_le_bytes());
+            buff.extend_from_slice(value.as_bytes());
+        }
+
+        // Set copies
+        buff.extend_from_slice(format!("^CN{:03}", self.copies).as_bytes());
+
+        // Start printing
+        buff.extend_from_slice(b"^FF");
+
+        Ok(buff)
+    }
+
+    /// Write the command sequence to a file for inspection
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, self.data()?)?;
+        Ok(())
+    }
+}
+
+impl PTouch {
+    /// Fill and print a template stored on the device, only supported on some
+    /// devices (see [crate::device::PTouchDevice::supports_template])
+    pub fn print_template(&mut self, job: &TemplateJob) -> Result<(), Error> {
+        if !self.options.device.supports_template() {
+            return Err(Error::UnsupportedMode(format!(
+                "P-touch Template is not supported on {:?}",
+                self.options.device
+            )));
+        }
+
+        debug!("Printing template: {:?}", job);
+
+        let data = job.data()?;
+        self.write(&data, self.timeout)?;
+
+        self.print_wait(&mut |_| (), &CancelToken::default())?;
+
+        // Return to raster mode for subsequent raster prints
+        self.switch_mode(Mode::Raster)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_template_job() {
+        let mut j = TemplateJob::new(3);
+        j.field("name".parse().unwrap(), "Bob")
+            .field("2".parse().unwrap(), "A1")
+            .copies(2);
+
+        let mut expected = vec![0x1b, 0x69, 0x61, 0x03];
+        expected.extend_from_slice(b"^II^TS003^ONname\0^DI\x03\x00Bob^OS002^DI\x02\x00A1^CN002^FF");
+
+        assert_eq!(j.data().unwrap(), expected);
+
+        // Out of range parameters are rejected
+        j.copies(1000);
+        assert!(matches!(j.data(), Err(Error::InvalidTemplate(_))));
+        j.copies(1);
+
+        j.field(Object::Index(1), &"a".repeat(u16::MAX as usize + 1));
+        assert!(matches!(j.data(), Err(Error::InvalidTemplate(_))));
+
+        assert!(TemplateJob::new(0).data().is_err());
+        assert!(TemplateJob::new(100).data().is_err());
+    }
+}
@@ -3,12 +3,23 @@
 // https://github.com/ryankurte/rust-ptouch
 // Copyright 2021 Ryan Kurte
 
-use clap::{Parser, Subcommand};
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
 use log::{debug, info, warn};
 use simplelog::{ColorChoice, LevelFilter, TermLogger, TerminalMode};
 
-use ptouch::device::{Media, PrintInfo, Status};
-use ptouch::render::{FontKind, Op, Render, RenderConfig};
+use ptouch::device::{AutoPowerOff, CutMode, Media, PrintInfo, Settings, Status};
+use ptouch::doctor;
+use ptouch::escp::{self, EscP};
+use ptouch::media::MediaRegistry;
+use ptouch::preflight::Report;
+use ptouch::render::{
+    FontFace, FontKind, FontSize, MissingGlyph, Op, Orientation, Render, RenderConfig, Rotation,
+    TextOptions, Wrap,
+};
+use ptouch::spool::{Spool, SpoolJob};
+use ptouch::template::{Object, TemplateJob};
 use ptouch::{render::RenderTemplate, Options, PTouch};
 
 #[derive(Clone, Debug, PartialEq, Parser)]
@@ -27,10 +38,126 @@ pub struct Flags {
     /// Default media kind when unable to query this from printer
     media: Media,
 
+    #[arg(long)]
+    /// Media profile file, adding to or overriding the built-in media types
+    media_config: Option<String>,
+
+    #[arg(long, default_value = "landscape")]
+    /// Label orientation (landscape, portrait)
+    orientation: Orientation,
+
+    #[arg(long)]
+    /// Wait up to the specified number of seconds for the printer to connect
+    wait: Option<u64>,
+
     #[arg(long, default_value = "info")]
     log_level: LevelFilter,
 }
 
+/// Text rendering options
+#[derive(Clone, Debug, PartialEq, Args)]
+pub struct TextArgs {
+    #[arg(long)]
+    /// Scalable font, `sans` for the embedded font or a TTF / OTF file, or a BDF / PCF bitmap font (overrides bitmap font)
+    face: Option<FontFace>,
+
+    #[arg(long, default_value = "1")]
+    /// Integer scale for bitmap fonts (eg. 2 for double size)
+    scale: u32,
+
+    #[arg(long, default_value = "20px")]
+    /// Scalable font size (eg. 12pt, 3mm, 20px)
+    size: FontSize,
+
+    #[arg(long, default_value = "128")]
+    /// Scalable font coverage threshold (0-255)
+    threshold: u8,
+
+    #[arg(long)]
+    /// Use the largest font (or font size for scalable fonts) that fits the label
+    fit: bool,
+
+    #[arg(long)]
+    /// Minimum font size when fitting text
+    min_size: Option<FontSize>,
+
+    #[arg(long)]
+    /// Maximum font size when fitting text
+    max_size: Option<FontSize>,
+
+    #[arg(long)]
+    /// Fallback fonts (TTF / OTF files) for characters missing from the selected font
+    fallback: Vec<FontFace>,
+
+    #[arg(long, default_value = "replace")]
+    /// Behaviour for characters missing from all fonts (replace, error)
+    missing: MissingGlyph,
+
+    #[arg(long)]
+    /// Bold text (using --bold-face, the embedded bold font, or synthetic bold)
+    bold: bool,
+
+    #[arg(long)]
+    /// Bold font (TTF / OTF file) for scalable fonts
+    bold_face: Option<FontFace>,
+
+    #[arg(long)]
+    /// Underline text
+    underline: bool,
+
+    #[arg(long)]
+    /// Strike through text
+    strikethrough: bool,
+
+    #[arg(long)]
+    /// Inverse text (white on a black box)
+    inverse: bool,
+
+    #[arg(long, default_value = "0", allow_hyphen_values = true)]
+    /// Additional spacing between characters in pixels
+    letter_spacing: i32,
+
+    #[arg(long, allow_hyphen_values = true)]
+    /// Spacing between lines in pixels (overrides the font default)
+    line_spacing: Option<i32>,
+
+    #[arg(long, default_value = "0")]
+    /// Text rotation in degrees clockwise (0, 90, 180, 270)
+    rotation: Rotation,
+
+    #[arg(long, default_value = "none")]
+    /// Break text into balanced lines (none, auto, or a number of lines)
+    wrap: Wrap,
+}
+
+impl TextArgs {
+    /// Build text options using the provided bitmap font
+    fn options(&self, font: FontKind) -> TextOptions {
+        TextOptions {
+            font,
+            face: self.face.clone(),
+            scale: self.scale,
+            size: self.size,
+            threshold: self.threshold,
+            fit: self.fit,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            fallback: self.fallback.clone(),
+            missing: self.missing,
+            bold: self.bold,
+            bold_face: self.bold_face.clone(),
+            underline: self.underline,
+            strikethrough: self.strikethrough,
+            inverse: self.inverse,
+            letter_spacing: self.letter_spacing,
+            line_spacing: self.line_spacing,
+            rotation: self.rotation,
+            wrap: self.wrap,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Subcommand)]
 pub enum RenderCommand {
     /// Basic text rendering
@@ -40,6 +167,9 @@ pub enum RenderCommand {
         #[arg(value_enum, default_value = "12x16")]
         /// Text font
         font: FontKind,
+
+        #[command(flatten)]
+        text_args: TextArgs,
     },
     /// QR Code with text
     QrText {
@@ -52,6 +182,9 @@ pub enum RenderCommand {
         #[arg(value_enum, default_value = "12x16")]
         /// Text font
         font: FontKind,
+
+        #[command(flatten)]
+        text_args: TextArgs,
     },
     /// QR Code
     Qr {
@@ -112,12 +245,160 @@ pub enum Command {
         /// Do not feed and cut label after printing to avoid waste
         chain: bool,
 
+        #[arg(long, default_value = "1")]
+        /// Number of copies to print
+        copies: u16,
+
+        #[arg(long, conflicts_with = "half_cut")]
+        /// Cut after every N copies (1-99) rather than each copy (not supported on all devices)
+        cut_every: Option<u8>,
+
+        #[arg(long)]
+        /// Half-cut between copies, with a full cut after the last copy (not supported on all devices)
+        half_cut: bool,
+
         #[command(subcommand)]
         cmd: RenderCommand,
     },
 
     /// Feed and cut label, can be used with the "chain" option for print
     Cut,
+
+    /// Print text using the printer's built-in fonts (ESC/P mode, not supported on all devices)
+    Escp {
+        /// Text value
+        text: String,
+
+        #[arg(long, value_enum, default_value = "helsinki")]
+        /// Resident font
+        font: escp::Font,
+
+        #[arg(long, default_value = "32")]
+        /// Character size in dots
+        size: u16,
+
+        #[arg(long)]
+        /// Bold text
+        bold: bool,
+
+        #[arg(long)]
+        /// Italic text
+        italic: bool,
+
+        #[arg(long)]
+        /// Barcode value, printed after the text
+        barcode: Option<String>,
+
+        #[arg(long, value_enum, default_value = "code39")]
+        /// Barcode kind
+        barcode_kind: escp::BarcodeKind,
+
+        #[arg(long)]
+        /// Write ESC/P commands to a file instead of printing
+        dump: Option<String>,
+    },
+
+    /// Check device visibility, permissions and driver binding
+    Doctor {
+        #[arg(long)]
+        /// Output udev rules for all supported devices
+        udev: bool,
+    },
+
+    /// Spool jobs to disk, printing them when the printer is available
+    Spool {
+        #[arg(long, default_value = "ptouch-spool")]
+        /// Spool directory
+        dir: String,
+
+        #[command(subcommand)]
+        cmd: SpoolCommand,
+    },
+
+    /// Read or write persistent printer settings
+    Settings {
+        #[command(subcommand)]
+        cmd: SettingsCommand,
+    },
+
+    /// Fill and print a template stored on the printer (P-touch Template mode, not supported on all devices)
+    Stored {
+        /// Template key (1-99)
+        key: u8,
+
+        #[arg(long = "field", value_parser = parse_field)]
+        /// Object data as OBJECT=VALUE, where OBJECT is an object name or number
+        fields: Vec<(Object, String)>,
+
+        #[arg(long, default_value = "1")]
+        /// Number of copies to print
+        copies: u16,
+
+        #[arg(long)]
+        /// Write template commands to a file instead of printing
+        dump: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Subcommand)]
+pub enum SpoolCommand {
+    /// Add a job to the spool
+    Add {
+        #[arg(long)]
+        /// Do not feed and cut label after printing to avoid waste
+        chain: bool,
+
+        #[arg(long, default_value = "1")]
+        /// Number of copies to print
+        copies: u16,
+
+        #[arg(long, conflicts_with = "half_cut")]
+        /// Cut after every N copies (1-99) rather than each copy (not supported on all devices)
+        cut_every: Option<u8>,
+
+        #[arg(long)]
+        /// Half-cut between copies, with a full cut after the last copy (not supported on all devices)
+        half_cut: bool,
+
+        #[command(subcommand)]
+        cmd: Box<RenderCommand>,
+    },
+    /// Print spooled jobs whenever a printer is connected
+    Run {
+        #[arg(long, default_value = "3")]
+        /// Number of attempts before a job is moved to the failed directory
+        max_attempts: u32,
+
+        #[arg(long)]
+        /// Exit once the spool is empty
+        once: bool,
+    },
+    /// List spooled and failed jobs
+    List,
+}
+
+#[derive(Clone, Debug, PartialEq, Subcommand)]
+pub enum SettingsCommand {
+    /// Fetch printer settings
+    Get,
+    /// Update printer settings, unspecified settings are left unchanged
+    Set {
+        #[arg(long)]
+        /// Auto power-off time on battery in minutes (off, 10, 20, 30, 40, 50, 60)
+        auto_power_off_battery: Option<AutoPowerOff>,
+
+        #[arg(long)]
+        /// Auto power-off time on AC adaptor in minutes (off, 10, 20, 30, 40, 50, 60)
+        auto_power_off_ac: Option<AutoPowerOff>,
+    },
+}
+
+/// Parse an OBJECT=VALUE template field
+fn parse_field(s: &str) -> Result<(Object, String), String> {
+    match s.split_once('=') {
+        Some((o, v)) => Ok((o.parse().unwrap(), v.to_string())),
+        None => Err(format!("Invalid field '{}', expected OBJECT=VALUE", s)),
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -133,16 +414,51 @@ fn main() -> anyhow::Result<()> {
     )
     .unwrap();
 
+    // Diagnostics must run without holding the device
+    if let Command::Doctor { udev } = &opts.command {
+        if *udev {
+            print!("{}", doctor::udev_rules());
+            return Ok(());
+        }
+
+        let checks = doctor::diagnose();
+        for c in &checks {
+            println!("[{:?}] {}: {}", c.outcome, c.name, c.detail);
+        }
+
+        if checks.iter().any(|c| c.outcome == doctor::Outcome::Fail) {
+            return Err(anyhow::anyhow!("Device checks failed"));
+        }
+
+        return Ok(());
+    }
+
+    // Load media profiles
+    let mut registry = MediaRegistry::default();
+    if let Some(f) = &opts.media_config {
+        registry.load(f)?;
+    }
+
+    // Spool commands manage their own connections
+    if let Command::Spool { dir, cmd } = &opts.command {
+        return run_spool(&opts, registry, dir, cmd);
+    }
+
     // Create default render configuration
     let mut rc = RenderConfig {
         y: opts.media.area().1 as usize,
+        orientation: opts.orientation,
         ..Default::default()
     };
 
     debug!("Connecting to PTouch device: {:?}", opts.options);
 
     // Attempt to connect to ptouch device to inform configuration
-    let connect = match PTouch::new(&opts.options) {
+    let pt = match opts.wait {
+        Some(s) => PTouch::wait_for(&opts.options, Duration::from_secs(s)),
+        None => PTouch::new(&opts.options),
+    };
+    let connect = match pt {
         Ok(mut pt) => {
             let status;
             if opts.options.no_status_fetch {
@@ -157,11 +473,13 @@ fn main() -> anyhow::Result<()> {
                 info!("Device status (fetched from device): {:?}", status);
             }
 
-            // Build MediaWidth from status message to retrieve offsets
-            let media = Media::from((status.media_kind, status.media_width));
+            // Lookup media profile from status message to retrieve offsets
+            let media = registry
+                .lookup(status.media_kind, status.media_width)?
+                .clone();
 
             // Update render config to reflect tape
-            rc.y = media.area().1 as usize;
+            rc.y = media.pins;
             // TODO: update colours too?
 
             // Return device and mediat width
@@ -227,6 +545,24 @@ fn main() -> anyhow::Result<()> {
 
             return Ok(());
         }
+        Command::Escp {
+            dump: Some(file), ..
+        } => {
+            // Write commands to file without printing
+            let e = opts.command.escp().unwrap();
+            e.save(file)?;
+
+            return Ok(());
+        }
+        Command::Stored {
+            dump: Some(file), ..
+        } => {
+            // Write commands to file without printing
+            let j = opts.command.template().unwrap();
+            j.save(file)?;
+
+            return Ok(());
+        }
         _ => (),
     }
 
@@ -247,7 +583,13 @@ fn main() -> anyhow::Result<()> {
         Command::Status => {
             println!("Status: {:?}", status);
         }
-        Command::Print { chain, cmd } => {
+        Command::Print {
+            chain,
+            copies,
+            cut_every,
+            half_cut,
+            cmd,
+        } => {
             // Load render operations from command
             let ops = cmd.load(opts.pad)?;
 
@@ -260,12 +602,22 @@ fn main() -> anyhow::Result<()> {
             // Generate raster data for printing
             let data = r.raster(media.area())?;
 
+            // Check the rendered label matches the loaded media using the status fetched
+            // on connection, device status and raster data are re-checked (and warnings
+            // reported) before printing
+            if !opts.options.no_status_fetch {
+                Report::new(&status, &registry, r.size().height as usize).into_result()?;
+            }
+            ptouch.set_media_registry(registry.clone());
+
             // Setup print info based on media and rastered data
             let info = PrintInfo {
                 width: Some(status.media_width),
-                length: Some(0),
+                length: Some(media.length.unwrap_or(0)),
                 raster_no: data.len() as u32,
                 chain: *chain,
+                copies: *copies,
+                cut: cut_mode(*cut_every, *half_cut),
                 ..Default::default()
             };
 
@@ -276,31 +628,235 @@ fn main() -> anyhow::Result<()> {
             let info = PrintInfo {
                 width: Some(status.media_width),
                 length: Some(0),
-                raster_no: media.area().1 as u32,
+                raster_no: media.pins as u32,
                 ..Default::default()
             };
             ptouch.cut(&info)?;
         }
+        Command::Escp { .. } => {
+            let e = opts.command.escp().unwrap();
+            ptouch.print_escp(&e)?;
+        }
+        Command::Settings {
+            cmd: SettingsCommand::Get,
+        } => {
+            let s = ptouch.settings()?;
+            println!("Settings: {:?}", s);
+        }
+        Command::Settings {
+            cmd:
+                SettingsCommand::Set {
+                    auto_power_off_battery,
+                    auto_power_off_ac,
+                },
+        } => {
+            let s = Settings {
+                auto_power_off_battery: *auto_power_off_battery,
+                auto_power_off_ac: *auto_power_off_ac,
+            };
+            ptouch.set_settings(&s)?;
+            info!("Updated device settings: {:?}", s);
+        }
+        Command::Stored { .. } => {
+            let j = opts.command.template().unwrap();
+            ptouch.print_template(&j)?;
+        }
         _ => (),
     }
 
-    // TODO: close the printer?
+    // Reset the printer and hand it back to the kernel
+    ptouch.close()?;
 
     Ok(())
 }
 
+/// Resolve the cut mode from print arguments
+fn cut_mode(cut_every: Option<u8>, half_cut: bool) -> CutMode {
+    match (cut_every, half_cut) {
+        (Some(n), _) => CutMode::Every(n),
+        (None, true) => CutMode::HalfCut,
+        (None, false) => CutMode::Each,
+    }
+}
+
+/// Interval at which the spool runner checks for new jobs
+const SPOOL_POLL: Duration = Duration::from_secs(5);
+
+/// Time to wait for the printer before re-checking the spool
+const SPOOL_WAIT: Duration = Duration::from_secs(60);
+
+fn run_spool(
+    opts: &Flags,
+    registry: MediaRegistry,
+    dir: &str,
+    cmd: &SpoolCommand,
+) -> anyhow::Result<()> {
+    let mut spool = Spool::new(dir)?;
+    spool.set_media(registry);
+
+    match cmd {
+        SpoolCommand::Add {
+            chain,
+            copies,
+            cut_every,
+            half_cut,
+            cmd,
+        } => {
+            let ops = cmd.load(opts.pad)?;
+
+            // Media, length and raster fields are set when the job is printed
+            let info = PrintInfo {
+                chain: *chain,
+                copies: *copies,
+                cut: cut_mode(*cut_every, *half_cut),
+                ..Default::default()
+            };
+
+            let p = spool.submit(&SpoolJob::ops(ops, opts.orientation, info))?;
+            info!("Spooled job: {}", p.display());
+        }
+        SpoolCommand::List => {
+            for p in spool.pending()? {
+                println!("pending: {}", p.display());
+            }
+            for p in spool.failed()? {
+                println!("failed: {}", p.display());
+            }
+        }
+        SpoolCommand::Run { max_attempts, once } => loop {
+            // Wait for jobs
+            if spool.pending()?.is_empty() {
+                if *once {
+                    break;
+                }
+                std::thread::sleep(SPOOL_POLL);
+                continue;
+            }
+
+            // Wait for the printer
+            let mut ptouch = match PTouch::wait_for(&opts.options, SPOOL_WAIT) {
+                Ok(p) => p,
+                Err(ptouch::Error::Timeout) => {
+                    debug!("Waiting for printer");
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Error connecting to PTouch: {:?}", e);
+                    std::thread::sleep(SPOOL_POLL);
+                    continue;
+                }
+            };
+
+            let printed = match spool.drain(&mut ptouch, *max_attempts) {
+                Ok(r) => {
+                    info!("Spool drained: {:?}", r);
+                    r.printed
+                }
+                Err(e) => {
+                    warn!("Spool interrupted: {:?}", e);
+                    0
+                }
+            };
+
+            if let Err(e) = ptouch.close() {
+                debug!("Error closing PTouch: {:?}", e);
+            }
+
+            // Don't spin on jobs that are failing or waiting to be retried
+            if printed == 0 {
+                std::thread::sleep(SPOOL_POLL);
+            }
+        },
+    }
+
+    Ok(())
+}
+
+impl Command {
+    /// Build ESC/P commands for the `escp` subcommand
+    pub fn escp(&self) -> Option<EscP> {
+        let (text, font, size, bold, italic, barcode, barcode_kind) = match self {
+            Command::Escp {
+                text,
+                font,
+                size,
+                bold,
+                italic,
+                barcode,
+                barcode_kind,
+                ..
+            } => (text, font, size, bold, italic, barcode, barcode_kind),
+            _ => return None,
+        };
+
+        // Fix for escaped newlines from shell
+        let text = text.replace("\\n", "\n");
+
+        let mut e = EscP::new();
+        e.font(*font)
+            .size(*size)
+            .bold(*bold)
+            .italic(*italic)
+            .text(&text);
+
+        if let Some(code) = barcode {
+            let opts = escp::BarcodeOptions {
+                kind: *barcode_kind,
+                ..Default::default()
+            };
+            e.newline().barcode(code, &opts);
+        }
+
+        Some(e)
+    }
+
+    /// Build a template job for the `stored` subcommand
+    pub fn template(&self) -> Option<TemplateJob> {
+        match self {
+            Command::Stored {
+                key,
+                fields,
+                copies,
+                ..
+            } => {
+                let mut j = TemplateJob::new(*key);
+                for (o, v) in fields {
+                    j.field(o.clone(), v);
+                }
+                j.copies(*copies);
+
+                Some(j)
+            }
+            _ => None,
+        }
+    }
+}
+
 impl RenderCommand {
     pub fn load(&self, pad: usize) -> Result<Vec<Op>, anyhow::Error> {
         match self {
-            RenderCommand::Text { text, font } => {
-                let ops = vec![Op::pad(pad), Op::text_with_font(text, *font), Op::pad(pad)];
+            RenderCommand::Text {
+                text,
+                font,
+                text_args,
+            } => {
+                let ops = vec![
+                    Op::pad(pad),
+                    Op::text_with_options(text, text_args.options(*font)),
+                    Op::pad(pad),
+                ];
                 Ok(ops)
             }
-            RenderCommand::QrText { qr, text, font } => {
+            RenderCommand::QrText {
+                qr,
+                text,
+                font,
+                text_args,
+            } => {
                 let ops = vec![
                     Op::pad(pad),
                     Op::qr(qr),
-                    Op::text_with_font(text, *font),
+                    Op::text_with_options(text, text_args.options(*font)),
                     Op::pad(pad),
                 ];
                 Ok(ops)